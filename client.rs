@@ -0,0 +1,916 @@
+use clap::ValueEnum;
+use futures_util::{Stream, StreamExt};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use tokio::time::{Duration, Instant, sleep, sleep_until};
+use tracing::{debug, info, warn};
+
+use crate::config::{ApiKey, Config};
+use crate::error::{FetchError, HttpError};
+
+#[derive(Serialize)]
+pub struct Prompt {
+    pub model: String,
+    pub prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    // Ollama's output constraint, e.g. "json"; servers that predate it
+    // ignore the field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<SamplingOptions>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct SamplingOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    // Ollama's cap on generated tokens; sent as max_tokens to the other APIs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<u32>,
+}
+
+impl SamplingOptions {
+    // None when nothing is set, so the request body stays unchanged
+    pub fn new(
+        temperature: Option<f32>,
+        top_p: Option<f32>,
+        seed: Option<u64>,
+        num_predict: Option<u32>,
+    ) -> Option<Self> {
+        if temperature.is_none() && top_p.is_none() && seed.is_none() && num_predict.is_none() {
+            return None;
+        }
+        Some(SamplingOptions {
+            temperature,
+            top_p,
+            seed,
+            num_predict,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ResponseChunk {
+    response: String,
+    done: bool,
+    // Only present on Ollama's final chunk
+    eval_count: Option<u64>,
+    prompt_eval_count: Option<u64>,
+    total_duration: Option<u64>,
+}
+
+impl ResponseChunk {
+    fn log_usage(&self) {
+        if self.eval_count.is_none() && self.prompt_eval_count.is_none() {
+            return;
+        }
+        let count = |n: Option<u64>| n.map_or("?".to_string(), |n| n.to_string());
+        info!(
+            "Tokens: {} prompt, {} generated{}",
+            count(self.prompt_eval_count),
+            count(self.eval_count),
+            self.total_duration
+                .map(|ns| format!(", {:.2}s", Duration::from_nanos(ns).as_secs_f64()))
+                .unwrap_or_default()
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage<'a>>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct ChatMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatChunk {
+    choices: Vec<ChatChoice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatChoice {
+    #[serde(default)]
+    delta: ChatDelta,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ChatDelta {
+    content: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<&'a str>,
+    messages: [ChatMessage<'a>; 1],
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+}
+
+// One `data:` payload of the Messages API stream; only text deltas and the
+// final message_stop matter here
+#[derive(Deserialize, Debug)]
+struct MessagesEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    delta: Option<MessagesDelta>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessagesDelta {
+    text: Option<String>,
+}
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+// The Messages API requires a length limit; used when --max-tokens is unset
+const ANTHROPIC_MAX_TOKENS: u32 = 4096;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApiFormat {
+    /// Ollama /api/generate with newline-delimited JSON chunks
+    Ollama,
+    /// OpenAI-compatible /v1/chat/completions with server-sent events
+    Openai,
+    /// Anthropic /v1/messages with server-sent events; needs --api-key
+    Anthropic,
+}
+
+impl ApiFormat {
+    // Guesses the protocol from the endpoint URL; anything unrecognised is
+    // taken to be Ollama, the original default
+    pub fn from_url(url: &str) -> Self {
+        let url = url.trim().trim_end_matches('/').to_lowercase();
+        if url.contains("anthropic.com") || url.ends_with("/v1/messages") {
+            ApiFormat::Anthropic
+        } else if url.ends_with("/chat/completions") {
+            ApiFormat::Openai
+        } else {
+            ApiFormat::Ollama
+        }
+    }
+
+    fn build_request(
+        self,
+        client: &Client,
+        config: &Config,
+        prompt: &Prompt,
+    ) -> reqwest::RequestBuilder {
+        let request = client.post(&config.api_url);
+        let request = match (&config.api_key, self) {
+            (Some(ApiKey(key)), ApiFormat::Anthropic) => request.header("x-api-key", key),
+            (Some(ApiKey(key)), _) => request.bearer_auth(key),
+            (None, _) => request,
+        };
+
+        match self {
+            ApiFormat::Ollama => request.json(prompt),
+            ApiFormat::Anthropic => request
+                .header("anthropic-version", ANTHROPIC_VERSION)
+                .json(&MessagesRequest {
+                    model: &prompt.model,
+                    max_tokens: prompt
+                        .options
+                        .and_then(|o| o.num_predict)
+                        .unwrap_or(ANTHROPIC_MAX_TOKENS),
+                    system: prompt.system.as_deref(),
+                    messages: [ChatMessage {
+                        role: "user",
+                        content: &prompt.prompt,
+                    }],
+                    stream: true,
+                    temperature: prompt.options.and_then(|o| o.temperature),
+                    top_p: prompt.options.and_then(|o| o.top_p),
+                }),
+            ApiFormat::Openai => request.json(&ChatRequest {
+                model: &prompt.model,
+                messages: prompt
+                    .system
+                    .iter()
+                    .map(|system| ChatMessage {
+                        role: "system",
+                        content: system,
+                    })
+                    .chain(std::iter::once(ChatMessage {
+                        role: "user",
+                        content: &prompt.prompt,
+                    }))
+                    .collect(),
+                stream: true,
+                temperature: prompt.options.and_then(|o| o.temperature),
+                top_p: prompt.options.and_then(|o| o.top_p),
+                seed: prompt.options.and_then(|o| o.seed),
+                max_tokens: prompt.options.and_then(|o| o.num_predict),
+            }),
+        }
+    }
+
+    // Maps one line of the response stream onto a ResponseChunk. Lines that
+    // carry no content (SSE comments, event names) yield None.
+    fn parse_stream_line(
+        self,
+        line: &str,
+    ) -> Result<Option<ResponseChunk>, serde_json::Error> {
+        match self {
+            ApiFormat::Ollama => serde_json::from_str(line).map(Some),
+            ApiFormat::Openai => {
+                let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+                    return Ok(None);
+                };
+                if data == "[DONE]" {
+                    return Ok(Some(ResponseChunk {
+                        done: true,
+                        ..Default::default()
+                    }));
+                }
+                let chunk: ChatChunk = serde_json::from_str(data)?;
+                Ok(Some(ResponseChunk {
+                    response: chunk
+                        .choices
+                        .into_iter()
+                        .filter_map(|choice| choice.delta.content)
+                        .collect(),
+                    ..Default::default()
+                }))
+            }
+            ApiFormat::Anthropic => {
+                let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+                    return Ok(None);
+                };
+                let event: MessagesEvent = serde_json::from_str(data)?;
+                match event.kind.as_str() {
+                    "content_block_delta" => Ok(Some(ResponseChunk {
+                        response: event.delta.and_then(|d| d.text).unwrap_or_default(),
+                        ..Default::default()
+                    })),
+                    "message_stop" => Ok(Some(ResponseChunk {
+                        done: true,
+                        ..Default::default()
+                    })),
+                    _ => Ok(None),
+                }
+            }
+        }
+    }
+}
+
+pub fn build_client(config: &Config) -> Result<Client, FetchError> {
+    Ok(Client::builder()
+        .default_headers(config.headers.clone())
+        .timeout(config.timeout)
+        .read_timeout(config.read_timeout)
+        .gzip(config.compression)
+        .deflate(config.compression)
+        .build()?)
+}
+
+#[derive(Deserialize)]
+struct TagList {
+    #[serde(default)]
+    models: Vec<TagEntry>,
+}
+
+#[derive(Deserialize)]
+struct TagEntry {
+    name: String,
+}
+
+// The /api/tags endpoint that sits next to the configured /api/generate
+fn tags_url(api_url: &str) -> String {
+    match api_url.trim_end_matches('/').strip_suffix("/generate") {
+        Some(base) => format!("{}/tags", base),
+        None => format!("{}/api/tags", api_url.trim_end_matches('/')),
+    }
+}
+
+// Confirms the configured model is installed on the Ollama server, so a
+// typo fails up front instead of as a stream of parse warnings
+pub async fn check_model_installed(client: &Client, config: &Config) -> Result<(), FetchError> {
+    let url = tags_url(&config.api_url);
+    let mut request = client.get(&url);
+    if let Some(ApiKey(key)) = &config.api_key {
+        request = request.bearer_auth(key);
+    }
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(status_error(response).await);
+    }
+    let tags: TagList = serde_json::from_slice(&response.bytes().await?)?;
+
+    // Ollama reports untagged models as name:latest
+    let wanted = if config.model.contains(':') {
+        config.model.clone()
+    } else {
+        format!("{}:latest", config.model)
+    };
+    if tags.models.iter().any(|m| m.name == config.model || m.name == wanted) {
+        debug!("Model {} is available at {}", config.model, url);
+        return Ok(());
+    }
+
+    let mut available: Vec<&str> = tags.models.iter().map(|m| m.name.as_str()).collect();
+    available.sort();
+    let listing = if available.is_empty() {
+        "none".to_string()
+    } else {
+        available.join(", ")
+    };
+    Err(FetchError::Config(format!(
+        "model '{}' is not installed on the server (available: {}); \
+         use --model to pick one or --no-preflight to skip this check",
+        config.model, listing
+    )))
+}
+
+// Where prompts get answered. The exploration code only needs the full text
+// of each reply, so it can run against anything that provides one.
+pub trait ModelClient {
+    fn generate(&self, prompt: &Prompt) -> impl Future<Output = Result<String, FetchError>> + Send;
+}
+
+// The configured HTTP endpoint, with retries and the response cache
+pub struct HttpModel<'a> {
+    pub client: Client,
+    pub config: &'a Config,
+}
+
+impl ModelClient for HttpModel<'_> {
+    async fn generate(&self, prompt: &Prompt) -> Result<String, FetchError> {
+        generate(&self.client, self.config, prompt).await
+    }
+}
+
+// A token bucket holding one token: requests are spaced evenly at the
+// configured rate, and concurrent callers queue for their turn.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    next_slot: tokio::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        RateLimiter {
+            interval: Duration::from_secs(60) / per_minute.max(1),
+            next_slot: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    pub async fn acquire(&self) {
+        let mut next_slot = self.next_slot.lock().await;
+        let now = Instant::now();
+        if *next_slot > now {
+            debug!("Rate limit: waiting {:?}", *next_slot - now);
+            sleep_until(*next_slot).await;
+        }
+        *next_slot = (*next_slot).max(now) + self.interval;
+    }
+}
+
+// One line of the --audit-log trace
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    timestamp: f64,
+    model: &'a str,
+    prompt: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<&'a str>,
+    response_len: usize,
+    complete: bool,
+    duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generated_tokens: Option<u64>,
+}
+
+// Appends one JSON line per answered request. Never fails the run: a trace
+// that can't be written is only logged.
+fn append_audit_entry(
+    path: &std::path::Path,
+    prompt: &Prompt,
+    streamed: &StreamedText,
+    took: Duration,
+) {
+    let entry = AuditEntry {
+        timestamp: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64()),
+        model: &prompt.model,
+        prompt: &prompt.prompt,
+        system: prompt.system.as_deref(),
+        response_len: streamed.text.len(),
+        complete: streamed.complete,
+        duration_ms: took.as_millis() as u64,
+        prompt_tokens: streamed.prompt_tokens,
+        generated_tokens: streamed.generated_tokens,
+    };
+
+    let written = serde_json::to_string(&entry)
+        .map_err(io::Error::from)
+        .and_then(|mut line| {
+            line.push('\n');
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?
+                .write_all(line.as_bytes())
+        });
+    if let Err(e) = written {
+        warn!("Failed to append to audit log {}: {}", path.display(), e);
+    }
+}
+
+// Sends one prompt and returns the full streamed text, going through the
+// response cache when one is configured.
+pub async fn generate(
+    client: &Client,
+    config: &Config,
+    prompt: &Prompt,
+) -> Result<String, FetchError> {
+    let cache_path = config
+        .cache_dir
+        .as_ref()
+        .map(|dir| dir.join(format!("{}.txt", cache_key(config, prompt))));
+
+    if let Some(path) = &cache_path
+        && let Ok(text) = std::fs::read_to_string(path)
+    {
+        debug!("Cache hit: {}", path.display());
+        return Ok(text);
+    }
+
+    let mut attempt = 0;
+    let text = loop {
+        let started = std::time::Instant::now();
+        let response = send_with_retry(client, config, prompt).await?;
+        let streamed = get_streamed_text(response, config).await?;
+        if let Some(path) = &config.audit_log {
+            append_audit_entry(path, prompt, &streamed, started.elapsed());
+        }
+        if streamed.complete {
+            break streamed.text;
+        }
+        // A truncated answer would be cached and parsed as if it were whole
+        if attempt >= config.retry_incomplete {
+            return Err(FetchError::Http(HttpError::Incomplete {
+                received: streamed.text.len(),
+            }));
+        }
+        attempt += 1;
+        warn!(
+            "Discarding incomplete response, re-sending (attempt {}/{})",
+            attempt, config.retry_incomplete
+        );
+    };
+
+    if let Some(path) = &cache_path {
+        let stored = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(path, &text));
+        if let Err(e) = stored {
+            warn!("Failed to write cache entry {}: {}", path.display(), e);
+        }
+    }
+
+    Ok(text)
+}
+
+// SHA-256 over everything that shapes the response: endpoint, protocol and
+// the serialized request.
+fn cache_key(config: &Config, prompt: &Prompt) -> String {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    hasher.update(config.api_url.as_bytes());
+    hasher.update(format!("{:?}", config.api_format).as_bytes());
+    hasher.update(serde_json::to_vec(prompt).unwrap_or_default());
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+const MAX_ERROR_BODY_LEN: usize = 300;
+
+// Reads the body of a failed response so the error says what the server
+// objected to, not just the status
+async fn status_error(response: reqwest::Response) -> FetchError {
+    let status = response.status();
+    let text = response.text().await.unwrap_or_default();
+    let mut body: String = text.trim().chars().take(MAX_ERROR_BODY_LEN).collect();
+    if text.trim().chars().count() > MAX_ERROR_BODY_LEN {
+        body.push('…');
+    }
+    FetchError::Http(HttpError::Status { status, body })
+}
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+// Only transient failures are retried; 4xx responses go straight back to the caller.
+async fn send_with_retry(
+    client: &Client,
+    config: &Config,
+    body: &Prompt,
+) -> Result<reqwest::Response, FetchError> {
+    let mut attempt = 0;
+
+    loop {
+        let request = config.api_format.build_request(client, config, body);
+        if let Some(limiter) = &config.rate_limit {
+            limiter.acquire().await;
+        }
+        let failure = match request.send().await {
+            Ok(response) if response.status().is_server_error() => {
+                if attempt >= config.max_retries {
+                    return Err(status_error(response).await);
+                }
+                format!("server returned {}", response.status())
+            }
+            Ok(response) if !response.status().is_success() => {
+                return Err(status_error(response).await);
+            }
+            Ok(response) => return Ok(response),
+            Err(e) if (e.is_connect() || e.is_timeout()) && attempt < config.max_retries => {
+                e.to_string()
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let delay = RETRY_BASE_DELAY * 2u32.pow(attempt);
+        attempt += 1;
+        warn!(
+            "Request failed ({}), retry {}/{} in {:?}",
+            failure, attempt, config.max_retries, delay
+        );
+        sleep(delay).await;
+    }
+}
+
+// Spinner and byte count on stderr while a response streams in.
+struct Progress {
+    enabled: bool,
+    bytes: usize,
+    frame: usize,
+}
+
+impl Progress {
+    const FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+
+    fn new(enabled: bool) -> Self {
+        Progress {
+            enabled,
+            bytes: 0,
+            frame: 0,
+        }
+    }
+
+    fn tick(&mut self, bytes: usize) {
+        self.bytes += bytes;
+        if !self.enabled {
+            return;
+        }
+        self.frame = (self.frame + 1) % Self::FRAMES.len();
+        eprint!("\r{} receiving... {} bytes", Self::FRAMES[self.frame], self.bytes);
+        let _ = io::stderr().flush();
+    }
+
+    fn clear(&self) {
+        if self.enabled && self.bytes > 0 {
+            eprint!("\r\x1b[2K");
+            let _ = io::stderr().flush();
+        }
+    }
+}
+
+// Adds one raw stream line's text to `full_text` and returns the chunk if it
+// was the final one
+fn read_stream_line(
+    config: &Config,
+    raw: &[u8],
+    full_text: &mut String,
+    progress: &Progress,
+) -> Option<ResponseChunk> {
+    let line = String::from_utf8_lossy(raw);
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+
+    match config.api_format.parse_stream_line(line) {
+        Ok(None) => None,
+        Ok(Some(json_chunk)) => {
+            full_text.push_str(&json_chunk.response);
+            if !json_chunk.done {
+                return None;
+            }
+            progress.clear();
+            json_chunk.log_usage();
+            Some(json_chunk)
+        }
+        Err(e) => {
+            progress.clear();
+            warn!("Failed to parse line as JSON: {}\nError: {}", line, e);
+            None
+        }
+    }
+}
+
+// Text gathered from a stream; `complete` is false when it stopped before
+// `done`. Token counts are whatever the final chunk reported.
+#[derive(Debug)]
+pub struct StreamedText {
+    pub text: String,
+    pub complete: bool,
+    pub prompt_tokens: Option<u64>,
+    pub generated_tokens: Option<u64>,
+}
+
+impl StreamedText {
+    fn finished(text: String, last: ResponseChunk) -> Self {
+        StreamedText {
+            text,
+            complete: true,
+            prompt_tokens: last.prompt_eval_count,
+            generated_tokens: last.eval_count,
+        }
+    }
+
+    fn partial(text: String) -> Self {
+        StreamedText {
+            text,
+            complete: false,
+            prompt_tokens: None,
+            generated_tokens: None,
+        }
+    }
+}
+
+pub async fn get_streamed_text(
+    response: reqwest::Response,
+    config: &Config,
+) -> Result<StreamedText, FetchError> {
+    read_stream(response.bytes_stream(), config).await
+}
+
+// The line handling of get_streamed_text, over any stream of byte chunks
+async fn read_stream<B: AsRef<[u8]>>(
+    mut stream: impl Stream<Item = Result<B, reqwest::Error>> + Unpin,
+    config: &Config,
+) -> Result<StreamedText, FetchError> {
+    let mut full_text = String::new();
+    // Raw bytes, so a character split across chunks is decoded whole
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut progress = Progress::new(!config.quiet);
+    let mut chunks = 0;
+    let mut lines = 0;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                progress.clear();
+                // Nothing usable arrived, so report the transport error itself
+                if full_text.is_empty() {
+                    return Err(e.into());
+                }
+                warn!(
+                    "Stream failed after {} bytes: {}\nPartial response:\n{}",
+                    full_text.len(),
+                    e,
+                    full_text
+                );
+                return Ok(StreamedText::partial(full_text));
+            }
+        };
+        let chunk = chunk.as_ref();
+        progress.tick(chunk.len());
+        buffer.extend_from_slice(chunk);
+        chunks += 1;
+
+        while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+            let raw: Vec<u8> = buffer.drain(..=pos).collect();
+            lines += 1;
+            if let Some(last) = read_stream_line(config, &raw, &mut full_text, &progress) {
+                warn_if_buffered(config, chunks, lines);
+                return Ok(StreamedText::finished(full_text, last));
+            }
+        }
+    }
+
+    // The last line may arrive without its newline before the stream closes
+    if let Some(last) = read_stream_line(config, &buffer, &mut full_text, &progress) {
+        warn_if_buffered(config, chunks, lines + 1);
+        return Ok(StreamedText::finished(full_text, last));
+    }
+
+    progress.clear();
+    warn!(
+        "Stream closed before the model finished ({} bytes received)",
+        full_text.len()
+    );
+    Ok(StreamedText::partial(full_text))
+}
+
+// A multi-line reply that arrived as a single chunk was most likely held
+// back and sent in one piece by a proxy between us and the model
+fn warn_if_buffered(config: &Config, chunks: usize, lines: usize) {
+    if config.check_streaming && chunks == 1 && lines > 1 {
+        warn!(
+            "The whole response ({} lines) arrived in one chunk; \
+             a proxy may be buffering the stream",
+            lines
+        );
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time::{Duration, sleep};
+
+    use super::{ModelClient, Prompt};
+    use crate::error::{FetchError, HttpError};
+
+    #[derive(Clone)]
+    pub enum Reply {
+        Text(String),
+        // The stream dropped before the model was done
+        Incomplete,
+    }
+
+    // Answers each prompt with the first canned reply whose key occurs in it,
+    // so the exploration code can be tested without a server. Replies added
+    // with `once` go first and are used up by their first match. Every prompt
+    // is recorded, along with the most requests that were ever open at once.
+    #[derive(Default)]
+    pub struct MockModel {
+        replies: Mutex<Vec<(String, Reply, bool)>>,
+        delays: Mutex<Vec<(String, Duration)>>,
+        prompts: Mutex<Vec<String>>,
+        open: AtomicUsize,
+        most_open: AtomicUsize,
+    }
+
+    impl MockModel {
+        pub fn reply(self, key: &str, text: &str) -> Self {
+            self.add(key, Reply::Text(text.to_string()), false)
+        }
+
+        pub fn once(self, key: &str, reply: Reply) -> Self {
+            self.add(key, reply, true)
+        }
+
+        // Prompts containing `key` take `delay` to answer
+        pub fn delay(self, key: &str, delay: Duration) -> Self {
+            self.delays.lock().unwrap().push((key.to_string(), delay));
+            self
+        }
+
+        fn add(self, key: &str, reply: Reply, once: bool) -> Self {
+            self.replies.lock().unwrap().push((key.to_string(), reply, once));
+            self
+        }
+
+        // How many of the prompts sent so far contain `key`
+        pub fn sent(&self, key: &str) -> usize {
+            self.prompts.lock().unwrap().iter().filter(|p| p.contains(key)).count()
+        }
+
+        // Where the first prompt containing `key` came in the order sent
+        pub fn position(&self, key: &str) -> Option<usize> {
+            self.prompts.lock().unwrap().iter().position(|p| p.contains(key))
+        }
+
+        pub fn most_open(&self) -> usize {
+            self.most_open.load(Ordering::SeqCst)
+        }
+
+        fn answer(&self, prompt: &Prompt) -> Result<String, FetchError> {
+            let mut replies = self.replies.lock().unwrap();
+            let matching = |once: bool| {
+                replies.iter().position(|(key, _, is_once)| {
+                    *is_once == once && prompt.prompt.contains(key.as_str())
+                })
+            };
+            let found = matching(true)
+                .or_else(|| matching(false))
+                .ok_or_else(|| FetchError::Parse(format!("no reply for: {}", prompt.prompt)))?;
+            let reply = if replies[found].2 {
+                replies.remove(found).1
+            } else {
+                replies[found].1.clone()
+            };
+            match reply {
+                Reply::Text(text) => Ok(text),
+                Reply::Incomplete => Err(FetchError::Http(HttpError::Incomplete { received: 0 })),
+            }
+        }
+    }
+
+    impl ModelClient for MockModel {
+        async fn generate(&self, prompt: &Prompt) -> Result<String, FetchError> {
+            self.prompts.lock().unwrap().push(prompt.prompt.clone());
+            let open = self.open.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_open.fetch_max(open, Ordering::SeqCst);
+
+            let delay = self
+                .delays
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(key, _)| prompt.prompt.contains(key.as_str()))
+                .map(|(_, delay)| *delay);
+            if let Some(delay) = delay {
+                sleep(delay).await;
+            }
+
+            let answer = self.answer(prompt);
+            self.open.fetch_sub(1, Ordering::SeqCst);
+            answer
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+
+    const STREAM: &str = concat!(
+        r#"{"response": "Schrödinger's ", "done": false}"#,
+        "\n",
+        r#"{"response": "équation", "done": true}"#,
+        "\n"
+    );
+
+    #[test]
+    fn api_format_is_guessed_from_the_url() {
+        let guess = ApiFormat::from_url;
+        assert_eq!(guess("http://localhost:11434/api/generate"), ApiFormat::Ollama);
+        assert_eq!(guess("https://api.openai.com/v1/chat/completions"), ApiFormat::Openai);
+        assert_eq!(guess("http://gateway/v1/chat/completions/"), ApiFormat::Openai);
+        assert_eq!(guess("https://api.anthropic.com/v1/messages"), ApiFormat::Anthropic);
+        assert_eq!(guess("http://proxy.local/V1/Messages"), ApiFormat::Anthropic);
+        assert_eq!(guess("http://192.168.1.151/custom"), ApiFormat::Ollama);
+    }
+
+    async fn read_chunks(chunks: Vec<&[u8]>) -> StreamedText {
+        let chunks = stream::iter(chunks.into_iter().map(Ok::<_, reqwest::Error>));
+        read_stream(chunks, &Config::for_tests()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn characters_split_across_chunks_decode_whole() {
+        let whole = read_chunks(vec![STREAM.as_bytes()]).await;
+        assert_eq!(whole.text, "Schrödinger's équation");
+
+        let bytes = STREAM.as_bytes();
+        let inside_o = STREAM.find('ö').unwrap() + 1;
+        assert!(!STREAM.is_char_boundary(inside_o));
+        for cut in [inside_o, STREAM.find('é').unwrap() + 1] {
+            let split = read_chunks(vec![&bytes[..cut], &bytes[cut..]]).await;
+            assert_eq!(split.text, whole.text);
+            assert!(split.complete);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_last_line_without_a_newline_is_still_read() {
+        let unterminated = STREAM.trim_end();
+        let streamed = read_chunks(vec![unterminated.as_bytes()]).await;
+
+        assert_eq!(streamed.text, "Schrödinger's équation");
+        assert!(streamed.complete);
+    }
+}
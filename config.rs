@@ -0,0 +1,177 @@
+use reqwest::header::HeaderMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use reqwest::Url;
+use tracing::warn;
+
+use crate::client::{ApiFormat, RateLimiter};
+use crate::output::LiveText;
+use crate::error::FetchError;
+
+pub const OLLAMA_API_URL: &str = "http://192.168.1.151/api/generate";
+pub const MODEL: &str = "llama3.1:8b";
+pub const PROMPT_TEMPLATE: &str = "How does {} relate to other fields of science?";
+pub const EXTRACTION_SYSTEM: &str =
+    "You are a JSON extraction engine. Respond with only a JSON array, no prose.";
+pub const EXPAND_TEMPLATE: &str = "In the context of {context}, how does the concept '{concept}' relate to other scientific disciplines or subfields? List related concepts, define them, and provide examples.";
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub api_url: String,
+    pub model: String,
+    pub depth: usize,
+    pub breadth: Option<usize>,
+    pub max_runtime: Option<Duration>,
+    pub round_timeout: Option<Duration>,
+    pub max_retries: u32,
+    pub retry_incomplete: u32,
+    pub timeout: Duration,
+    pub read_timeout: Duration,
+    pub concurrency: usize,
+    // Shared by every clone so all requests draw from one budget
+    pub rate_limit: Option<Arc<RateLimiter>>,
+    pub api_format: ApiFormat,
+    pub api_key: Option<ApiKey>,
+    pub max_tokens: Option<u32>,
+    pub extraction_max_tokens: Option<u32>,
+    // Added to every request by the client; values are marked sensitive
+    pub headers: HeaderMap,
+    // Ask for gzip/deflate bodies; the client decompresses them transparently
+    pub compression: bool,
+    pub check_streaming: bool,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub seed: Option<u64>,
+    pub extraction_temperature: f32,
+    pub system: Option<String>,
+    pub extraction_system: String,
+    pub extract_chunk_size: Option<usize>,
+    pub quiet: bool,
+    pub prompt_template: String,
+    pub expand_template: String,
+    // Language the summaries should be written in (--lang)
+    pub lang: Option<String>,
+    pub cache_dir: Option<PathBuf>,
+    pub checkpoint: Option<PathBuf>,
+    // Directory for the unextracted summaries (--save-raw)
+    pub save_raw: Option<PathBuf>,
+    pub live_text: Option<Arc<LiveText>>,
+    pub audit_log: Option<PathBuf>,
+    pub stream_ndjson: bool,
+    pub no_extraction: bool,
+    pub strict_json: bool,
+    pub quote_values: bool,
+    // Set by the Ctrl-C handler; the expansion loop stops when it sees it
+    pub cancelled: Arc<AtomicBool>,
+}
+
+// Keeps the token out of Debug output so it can't leak into logs.
+#[derive(Clone)]
+pub struct ApiKey(pub(crate) String);
+
+impl ApiKey {
+    pub fn new(key: String) -> Self {
+        ApiKey(key)
+    }
+}
+
+impl std::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ApiKey(<redacted>)")
+    }
+}
+
+// Checked up front so a typo fails before any work instead of as a request
+// error later on. The URL itself is never echoed, since it may carry
+// credentials.
+pub fn validate_api_url(api_url: &str, has_api_key: bool) -> Result<(), FetchError> {
+    let url = Url::parse(api_url.trim())
+        .map_err(|e| FetchError::Config(format!("the API URL is not valid: {}", e)))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(FetchError::Config(format!(
+            "the API URL must start with http:// or https://, not {}:",
+            url.scheme()
+        )));
+    }
+    let Some(host) = url.host_str().filter(|h| !h.is_empty()) else {
+        return Err(FetchError::Config("the API URL has no host".to_string()));
+    };
+
+    let local = matches!(host, "localhost" | "127.0.0.1" | "[::1]");
+    if has_api_key && url.scheme() == "http" && !local {
+        warn!("The API key will be sent in cleartext over http to {}; use https", host);
+    }
+    Ok(())
+}
+
+pub fn validate_templates(prompt_template: &str, expand_template: &str) -> Result<(), FetchError> {
+    let placeholders = prompt_template.matches("{}").count();
+    if placeholders != 1 {
+        return Err(FetchError::Config(format!(
+            "--prompt-template must contain exactly one {{}} placeholder, found {}",
+            placeholders
+        )));
+    }
+
+    let placeholders = expand_template.matches("{concept}").count();
+    if placeholders != 1 {
+        return Err(FetchError::Config(format!(
+            "--expand-template must contain exactly one {{concept}} placeholder, found {}",
+            placeholders
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+impl Config {
+    // Defaults for tests: quiet, summary only, one request at a time
+    pub(crate) fn for_tests() -> Self {
+        Config {
+            api_url: "http://localhost:11434/api/generate".to_string(),
+            model: MODEL.to_string(),
+            depth: 0,
+            breadth: None,
+            max_runtime: None,
+            round_timeout: None,
+            max_retries: 0,
+            retry_incomplete: 0,
+            timeout: Duration::from_secs(120),
+            read_timeout: Duration::from_secs(60),
+            concurrency: 1,
+            rate_limit: None,
+            api_format: ApiFormat::Ollama,
+            api_key: None,
+            max_tokens: None,
+            extraction_max_tokens: None,
+            headers: HeaderMap::new(),
+            compression: true,
+            check_streaming: false,
+            temperature: None,
+            top_p: None,
+            seed: None,
+            extraction_temperature: 0.1,
+            system: None,
+            extraction_system: EXTRACTION_SYSTEM.to_string(),
+            extract_chunk_size: None,
+            quiet: true,
+            prompt_template: PROMPT_TEMPLATE.to_string(),
+            expand_template: EXPAND_TEMPLATE.to_string(),
+            lang: None,
+            cache_dir: None,
+            checkpoint: None,
+            save_raw: None,
+            live_text: None,
+            audit_log: None,
+            stream_ndjson: false,
+            no_extraction: false,
+            strict_json: false,
+            quote_values: false,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
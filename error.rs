@@ -0,0 +1,105 @@
+use std::io;
+
+#[derive(Debug)]
+pub enum FetchError {
+    Http(HttpError),
+    Json(serde_json::Error),
+    Yaml(serde_yaml::Error),
+    Io(io::Error),
+    Parse(String),
+    Config(String),
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    // The request never produced a usable response
+    Transport(reqwest::Error),
+    // The server answered with a non-2xx status; body is a trimmed snippet
+    Status {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    // The stream dropped or ended before the model reported it was done
+    Incomplete { received: usize },
+}
+
+impl FetchError {
+    // A connection that failed, timed out or was cut short; the same request
+    // may well succeed when it is sent again later
+    pub fn is_connection_failure(&self) -> bool {
+        match self {
+            FetchError::Http(HttpError::Incomplete { .. }) => true,
+            FetchError::Http(HttpError::Transport(e)) => {
+                e.is_connect() || e.is_timeout() || e.is_body() || e.is_decode()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpError::Transport(e) => write!(f, "{}", e),
+            HttpError::Status { status, body } if body.is_empty() => {
+                write!(f, "server returned {}", status)
+            }
+            HttpError::Status { status, body } => {
+                write!(f, "server returned {}: {}", status, body)
+            }
+            HttpError::Incomplete { received } => {
+                write!(f, "stream ended early after {} bytes", received)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::Http(e) => write!(f, "HTTP error: {}", e),
+            FetchError::Json(e) => write!(f, "JSON error: {}", e),
+            FetchError::Yaml(e) => write!(f, "YAML error: {}", e),
+            FetchError::Io(e) => write!(f, "I/O error: {}", e),
+            FetchError::Parse(msg) => write!(f, "Parse error: {}", msg),
+            FetchError::Config(msg) => write!(f, "Configuration error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FetchError::Http(HttpError::Transport(e)) => Some(e),
+            FetchError::Http(HttpError::Status { .. } | HttpError::Incomplete { .. }) => None,
+            FetchError::Json(e) => Some(e),
+            FetchError::Yaml(e) => Some(e),
+            FetchError::Io(e) => Some(e),
+            FetchError::Parse(_) | FetchError::Config(_) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> Self {
+        FetchError::Http(HttpError::Transport(e))
+    }
+}
+
+impl From<serde_json::Error> for FetchError {
+    fn from(e: serde_json::Error) -> Self {
+        FetchError::Json(e)
+    }
+}
+
+impl From<serde_yaml::Error> for FetchError {
+    fn from(e: serde_yaml::Error) -> Self {
+        FetchError::Yaml(e)
+    }
+}
+
+impl From<io::Error> for FetchError {
+    fn from(e: io::Error) -> Self {
+        FetchError::Io(e)
+    }
+}
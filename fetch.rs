@@ -1,300 +1,1452 @@
+use clap::error::{ContextKind, ErrorKind};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches, Id, Parser};
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use std::collections::{HashMap, HashSet};
-use std::io::{self, Write};
-use futures_util::StreamExt;
-use tokio;
-use tokio::time::{sleep, Duration};
+use std::ffi::OsString;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::time::Duration;
+use tracing::{info, warn};
 
-#[derive(Serialize)]
-struct Prompt {
-    model: String,
-    prompt: String,
-}
+use fetch::client::{
+    ApiFormat, HttpModel, ModelClient, RateLimiter, build_client, check_model_installed,
+};
+use fetch::config::{
+    ApiKey, Config, EXPAND_TEMPLATE, EXTRACTION_SYSTEM, MODEL, OLLAMA_API_URL, PROMPT_TEMPLATE,
+    validate_api_url, validate_templates,
+};
+use fetch::error::FetchError;
+use fetch::explore::{
+    StopReason, build_documentation, chunk_text, expansion_prompt, extract_insights,
+    extraction_prompt, topic_prompt,
+};
+use fetch::knowledge::{CasePolicy, GraphDiff, GraphOptions, Knowledge};
+use fetch::output::{
+    LiveText, OutputFormat, create_parent_dir, load_knowledge, markdown_anchor, read_aliases,
+    read_knowledge, relation_label, write_documentation_to_csv, write_documentation_to_dot,
+    write_documentation_to_file, write_documentation_to_gexf, write_documentation_to_html,
+    write_documentation_to_json, write_documentation_to_markdown, write_documentation_to_yaml,
+    write_json_atomically,
+};
 
-#[derive(Deserialize, Debug)]
-struct ResponseChunk {
-    response: String,
-    done: bool,
-}
+const API_URL_ENV: &str = "FETCH_API_URL";
+const MODEL_ENV: &str = "FETCH_MODEL";
+const API_KEY_ENV: &str = "FETCH_API_KEY";
 
-#[derive(Debug, Deserialize)]
-struct StructuredInsight {
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Document a science field by recursively querying an Ollama model"
+)]
+struct Args {
+    /// Field to document; read from piped stdin or asked for when omitted
     topic: Option<String>,
-    concept: Option<String>,
-    definition: Option<String>,
-    example: Option<String>,
-}
 
-#[derive(Default, Clone, Debug, PartialEq, Eq)]
-struct Concept {
-    definition: Option<String>,
-    examples: HashSet<String>,
-    related_concepts: HashSet<String>,
+    /// TOML file of option defaults, keyed by long flag name, e.g.
+    /// `api-url = "http://localhost:11434/api/generate"` or `format = ["text",
+    /// "json"]` [default: .fetch.toml when present]. Command-line flags win
+    /// over the file, which wins over environment variables and built-in
+    /// defaults. A flag on the command line replaces the file's value
+    /// entirely, lists included, and file entries that conflict with a flag
+    /// on the command line are ignored.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Endpoint to send prompts to [env: FETCH_API_URL] [default: http://192.168.1.151/api/generate]
+    #[arg(long)]
+    api_url: Option<String>,
+
+    /// Model to request from the endpoint [env: FETCH_MODEL] [default: llama3.1:8b]
+    #[arg(long)]
+    model: Option<String>,
+
+    /// Sampling temperature for the summary and expansion prompts
+    #[arg(long)]
+    temperature: Option<f32>,
+
+    /// Nucleus sampling cutoff for every prompt, between 0 and 1
+    #[arg(long)]
+    top_p: Option<f32>,
+
+    /// Sampling seed sent with every prompt. With a low temperature this
+    /// makes runs repeatable, but only while the server keeps the same
+    /// model version. Anthropic ignores it.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Sampling temperature for the JSON extraction prompts
+    #[arg(long, default_value_t = 0.1)]
+    extraction_temperature: f32,
+
+    /// System prompt for the summary and expansion prompts
+    #[arg(long)]
+    system: Option<String>,
+
+    /// System prompt for the JSON extraction prompts ("" to send none)
+    #[arg(long, default_value = EXTRACTION_SYSTEM)]
+    extraction_system: String,
+
+    /// Split summaries longer than this many characters into overlapping
+    /// pieces and extract each one separately
+    #[arg(long, value_name = "CHARS")]
+    extract_chunk_size: Option<usize>,
+
+    /// Number of expansion rounds after the initial summary (0 = summary only)
+    #[arg(long, default_value_t = 1)]
+    depth: usize,
+
+    /// Expand at most this many concepts per round, in name order; the
+    /// rest wait for later rounds
+    #[arg(long, value_name = "N")]
+    breadth: Option<usize>,
+
+    /// Stop starting new expansion rounds once the run has taken this long
+    #[arg(long, value_name = "SECS")]
+    max_runtime_secs: Option<u64>,
+
+    /// Abandon an expansion round that runs longer than this, keeping what
+    /// it finished; the unfinished concepts can be retried in a later round
+    #[arg(long, value_name = "SECS")]
+    round_timeout_secs: Option<u64>,
+
+    /// Output formats for the collected documentation, comma-separated or
+    /// repeated [default: text, or text,json,dot with --output-dir]
+    #[arg(long, value_enum, value_delimiter = ',')]
+    format: Vec<OutputFormat>,
+
+    /// Also print each extracted insight to stdout as one line of JSON
+    #[arg(long)]
+    stream_ndjson: bool,
+
+    /// Skip the JSON extraction round and store each summary verbatim: the
+    /// first under a concept named after the prompt, later ones as examples
+    #[arg(long, conflicts_with = "stream_ndjson")]
+    no_extraction: bool,
+
+    /// Reject extracted objects that carry fields other than topic, concept,
+    /// definition, example, relation and confidence
+    #[arg(long)]
+    strict_json: bool,
+
+    /// Wrap topics and concept names in double quotes when placing them in
+    /// a prompt. They are always stripped of control characters, collapsed
+    /// to single spaces and cut to 200 characters.
+    #[arg(long)]
+    quote_values: bool,
+
+    /// Seed the graph from a JSON or YAML graph written by an earlier run
+    #[arg(long, value_name = "FILE")]
+    resume: Option<PathBuf>,
+
+    /// Retries for connection errors, timeouts and 5xx responses
+    #[arg(long, default_value_t = 3)]
+    max_retries: u32,
+
+    /// Re-send a request this many times when its stream stops before the model is done
+    #[arg(long, default_value_t = 0)]
+    retry_incomplete: u32,
+
+    /// Overall time limit for each request, including the streamed body
+    #[arg(long, default_value_t = 120)]
+    timeout_secs: u64,
+
+    /// Give up on a stream that sends nothing for this long
+    #[arg(long, default_value_t = 60)]
+    read_timeout_secs: u64,
+
+    /// Number of requests to the model that may be open at once
+    #[arg(long, default_value_t = 4)]
+    concurrency: usize,
+
+    /// Send at most this many requests per minute, across exploration and extraction
+    #[arg(long, value_name = "REQS_PER_MIN")]
+    rate_limit: Option<u32>,
+
+    /// Request and stream protocol spoken by the endpoint [default: guessed
+    /// from the URL: .../chat/completions is openai, anthropic.com or
+    /// .../v1/messages is anthropic, anything else ollama]
+    #[arg(long, value_enum)]
+    api_format: Option<ApiFormat>,
+
+    /// Bearer token sent with every request, or x-api-key for Anthropic
+    /// [env: FETCH_API_KEY]
+    #[arg(long)]
+    api_key: Option<String>,
+
+    /// Extra header sent with every request, as "Name: Value"; repeatable
+    #[arg(long = "header", value_name = "NAME: VALUE")]
+    headers: Vec<String>,
+
+    /// Cap on the tokens generated for each summary and expansion prompt
+    /// (Ollama's num_predict). Anthropic requests need a cap and default to
+    /// 4096.
+    #[arg(long, value_name = "N")]
+    max_tokens: Option<u32>,
+
+    /// Cap on the tokens generated for each extraction prompt; too small a
+    /// cap cuts the JSON short
+    #[arg(long, value_name = "N")]
+    extraction_max_tokens: Option<u32>,
+
+    /// Record every relation in both directions instead of concept -> topic only
+    #[arg(long)]
+    bidirectional: bool,
+
+    /// How concept names are compared when merging them into the graph
+    #[arg(long, value_enum, default_value_t = CasePolicy::Sensitive)]
+    case_policy: CasePolicy,
+
+    /// Don't show streaming progress on stderr or log the per-round status
+    #[arg(long, short)]
+    quiet: bool,
+
+    /// Initial prompt; {} is replaced by the topic you enter
+    #[arg(long, default_value = PROMPT_TEMPLATE)]
+    prompt_template: String,
+
+    /// Prompt for expanding a concept; {concept} is the concept and {context} the initial prompt
+    #[arg(long, default_value = EXPAND_TEMPLATE)]
+    expand_template: String,
+
+    /// Ask for the summaries in this language, e.g. Spanish; the extraction
+    /// prompt stays English so its JSON keeps the same field names
+    #[arg(long, value_name = "LANGUAGE")]
+    lang: Option<String>,
+
+    /// Log more detail (-v for debug output including raw model responses, -vv for trace)
+    #[arg(long, short, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Cache model responses in this directory, keyed by a hash of the request
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Ignore --cache-dir and always query the model
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Print the prompts that would be sent and exit without calling the model
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Don't ask the server for gzip or deflate compressed responses
+    #[arg(long)]
+    no_compression: bool,
+
+    /// Warn when a whole multi-line response arrives in one piece, which
+    /// usually means a proxy is buffering the stream
+    #[arg(long)]
+    check_streaming: bool,
+
+    /// Skip checking /api/tags for the model before starting (Ollama only)
+    #[arg(long)]
+    no_preflight: bool,
+
+    /// Lowercase examples and collapse their whitespace before deduplicating
+    #[arg(long)]
+    normalize_examples: bool,
+
+    /// Cut examples longer than this many characters
+    #[arg(long, value_name = "CHARS")]
+    max_example_len: Option<usize>,
+
+    /// Stop adding new concepts once the graph holds this many
+    #[arg(long, value_name = "N")]
+    max_concepts: Option<usize>,
+
+    /// Keep only the latest definition of each concept instead of every
+    /// distinct one
+    #[arg(long)]
+    single_definition: bool,
+
+    /// Keep at most this many examples per concept, preferring the longest
+    #[arg(long, value_name = "N")]
+    max_examples_per_concept: Option<usize>,
+
+    /// Record at most this many related concepts per concept; later ones are
+    /// dropped
+    #[arg(long, value_name = "N")]
+    max_related_per_concept: Option<usize>,
+
+    /// JSON object mapping alias names to the concept they stand for
+    #[arg(long, value_name = "FILE")]
+    aliases: Option<PathBuf>,
+
+    /// Leave out concepts whose name contains this text, ignoring case;
+    /// repeat for several
+    #[arg(long, value_name = "TEXT")]
+    exclude_concept: Vec<String>,
+
+    /// Keep only concepts whose name contains one of these texts, ignoring
+    /// case; repeat for several
+    #[arg(long, value_name = "TEXT")]
+    include_only: Vec<String>,
+
+    /// Save the graph as JSON to this file after every expansion round
+    #[arg(long, value_name = "FILE")]
+    checkpoint: Option<PathBuf>,
+
+    /// Write each summary, before extraction, to a numbered file in this
+    /// directory (round_00_001.txt is the initial summary). With several
+    /// topics or models, each gets a subdirectory.
+    #[arg(long, value_name = "DIR")]
+    save_raw: Option<PathBuf>,
+
+    /// Append each concept to this text file once it has been expanded, so
+    /// the file grows while the run goes on; the rest follow when
+    /// exploration ends. Later additions to a written concept only appear
+    /// in the final output.
+    #[arg(long, value_name = "FILE")]
+    live_text: Option<PathBuf>,
+
+    /// Append one JSON line per model request (prompt, reply size, timing,
+    /// token counts) to this file
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+
+    /// Print concepts matching this term instead of writing the documentation;
+    /// with --resume the saved graph is searched without querying the model
+    #[arg(long, value_name = "TERM")]
+    query: Option<String>,
+
+    /// Print the N concepts with the most connections
+    #[arg(long, value_name = "N")]
+    show_top: Option<usize>,
+
+    /// Document every topic listed in this file, one per line
+    #[arg(long, value_name = "FILE", conflicts_with = "topic")]
+    topics_file: Option<PathBuf>,
+
+    /// Give each topic from --topics-file its own graph and output file,
+    /// named documentation-<topic>.<ext>
+    #[arg(long, requires = "topics_file")]
+    separate_outputs: bool,
+
+    /// Merge these saved JSON or YAML graphs into one file and exit; the
+    /// merged graph is YAML when -o ends in .yaml or .yml, JSON otherwise
+    #[arg(long, value_name = "FILE", num_args = 2.., conflicts_with_all = ["topic", "topics_file"])]
+    merge: Vec<PathBuf>,
+
+    /// Compare two saved JSON or YAML graphs, print what changed and exit
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["OLD", "NEW"],
+        conflicts_with_all = ["topic", "topics_file", "merge"]
+    )]
+    diff: Vec<PathBuf>,
+
+    /// Skip exploration and extract a graph from this file's text instead
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["topic", "topics_file", "merge", "diff", "interactive"]
+    )]
+    extract_file: Option<PathBuf>,
+
+    /// Explore the topic once with each of these models and print how the
+    /// later graphs differ from the first; each graph is written to
+    /// documentation-<model>.<ext>
+    #[arg(
+        long,
+        value_name = "MODELS",
+        value_delimiter = ',',
+        conflicts_with_all = [
+            "model", "topics_file", "merge", "diff", "extract_file", "interactive", "resume",
+            "output"
+        ]
+    )]
+    compare_models: Vec<String>,
+
+    /// File to write the documentation (or the --merge result) to, creating
+    /// missing directories [default: documentation.<ext>]
+    #[arg(short, long, value_name = "FILE", conflicts_with = "separate_outputs")]
+    output: Option<PathBuf>,
+
+    /// Add to an existing text output file, after a separator line, instead
+    /// of replacing it
+    #[arg(long)]
+    append: bool,
+
+    /// Directory to write documentation.<ext> into for every chosen format,
+    /// created if missing
+    #[arg(long, value_name = "DIR", conflicts_with = "output")]
+    output_dir: Option<PathBuf>,
+
+    /// After the first field, keep asking for more fields to add to the same
+    /// graph; the documentation is written at end of input
+    #[arg(long, conflicts_with = "separate_outputs")]
+    interactive: bool,
+
+    /// Print why exploration stopped and how many concepts were collected
+    #[arg(long)]
+    explain: bool,
+
+    /// Drop definitions and examples the model scored below this confidence
+    /// (0.0-1.0) before writing; unscored ones are kept
+    #[arg(long, value_name = "SCORE")]
+    min_confidence: Option<f64>,
+
+    /// Before writing, fold together concepts whose names differ only by
+    /// case or spacing, including ones from resumed or merged graphs
+    #[arg(long)]
+    normalize: bool,
+
+    /// Drop concepts with no definition, examples or related concepts before
+    /// writing
+    #[arg(long)]
+    min_content: bool,
+
+    /// Record in each concept the exploration prompts that produced it
+    /// (saved in JSON and YAML output as source_prompts)
+    #[arg(long)]
+    track_sources: bool,
+
+    /// Write only the concepts within --radius relations of this one
+    #[arg(long, value_name = "CONCEPT")]
+    focus: Option<String>,
+
+    /// Number of hops kept around the --focus concept
+    #[arg(long, default_value_t = 1, requires = "focus")]
+    radius: usize,
+
+    /// Also write the graph statistics to this JSON file
+    #[arg(long, value_name = "FILE", conflicts_with = "separate_outputs")]
+    stats_json: Option<PathBuf>,
 }
 
-#[derive(Default, Clone, Debug)]
-struct Knowledge {
-    concepts: HashMap<String, Concept>,
+impl TryFrom<&Args> for Config {
+    type Error = FetchError;
+
+    fn try_from(args: &Args) -> Result<Self, FetchError> {
+        validate_templates(&args.prompt_template, &args.expand_template)?;
+        validate_sampling(args)?;
+        if args.breadth == Some(0) {
+            return Err(FetchError::Config("--breadth must be at least 1".to_string()));
+        }
+        // clap leaves `requires` on a bool flag unchecked, so this is checked
+        // here for the command line and the config file alike
+        if args.separate_outputs && args.topics_file.is_none() {
+            return Err(FetchError::Config(
+                "--separate-outputs needs --topics-file".to_string(),
+            ));
+        }
+        if args.extract_chunk_size == Some(0) {
+            return Err(FetchError::Config(
+                "--extract-chunk-size must be at least 1".to_string(),
+            ));
+        }
+        if args.rate_limit == Some(0) {
+            return Err(FetchError::Config(
+                "--rate-limit must allow at least one request per minute".to_string(),
+            ));
+        }
+
+        let api_url = resolve_setting(args.api_url.clone(), API_URL_ENV, OLLAMA_API_URL);
+        let api_format = args.api_format.unwrap_or_else(|| ApiFormat::from_url(&api_url));
+        let config = Config {
+            api_url,
+            model: resolve_setting(args.model.clone(), MODEL_ENV, MODEL),
+            depth: args.depth,
+            breadth: args.breadth,
+            max_runtime: args.max_runtime_secs.map(Duration::from_secs),
+            round_timeout: args.round_timeout_secs.map(Duration::from_secs),
+            max_retries: args.max_retries,
+            retry_incomplete: args.retry_incomplete,
+            timeout: Duration::from_secs(args.timeout_secs),
+            read_timeout: Duration::from_secs(args.read_timeout_secs),
+            concurrency: args.concurrency,
+            rate_limit: args.rate_limit.map(|per_minute| Arc::new(RateLimiter::new(per_minute))),
+            api_format,
+            api_key: args
+                .api_key
+                .clone()
+                .or_else(|| env_setting(API_KEY_ENV))
+                .map(ApiKey::new),
+            max_tokens: args.max_tokens,
+            extraction_max_tokens: args.extraction_max_tokens,
+            headers: parse_headers(&args.headers)?,
+            compression: !args.no_compression,
+            check_streaming: args.check_streaming,
+            temperature: args.temperature,
+            top_p: args.top_p,
+            seed: args.seed,
+            extraction_temperature: args.extraction_temperature,
+            system: args.system.clone().filter(|s| !s.trim().is_empty()),
+            extraction_system: args.extraction_system.clone(),
+            extract_chunk_size: args.extract_chunk_size,
+            quiet: args.quiet,
+            prompt_template: args.prompt_template.clone(),
+            expand_template: args.expand_template.clone(),
+            lang: args.lang.clone().filter(|lang| !lang.trim().is_empty()),
+            cache_dir: args.cache_dir.clone().filter(|_| !args.no_cache),
+            checkpoint: args.checkpoint.clone(),
+            save_raw: args.save_raw.clone(),
+            live_text: args.live_text.clone().map(|path| Arc::new(LiveText::new(path))),
+            audit_log: args.audit_log.clone(),
+            stream_ndjson: args.stream_ndjson,
+            no_extraction: args.no_extraction,
+            strict_json: args.strict_json,
+            quote_values: args.quote_values,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        };
+
+        if config.api_format == ApiFormat::Anthropic && config.api_key.is_none() {
+            return Err(FetchError::Config(
+                "--api-format anthropic needs an API key (--api-key or FETCH_API_KEY)".to_string(),
+            ));
+        }
+        Ok(config)
+    }
 }
 
-impl Knowledge {
-    fn add_concept(&mut self, concept: String) {
-        self.concepts.entry(concept).or_default();
+const MAX_TOPIC_ATTEMPTS: usize = 3;
+const MAX_TOPIC_LEN: usize = 200;
+
+// Takes the field from the command line, then from piped input, and only
+// prompts when stdin is a terminal
+fn read_topic(arg: Option<&str>) -> Result<String, FetchError> {
+    if let Some(topic) = arg {
+        return check_topic(topic).map_err(FetchError::Config);
     }
 
-    fn add_related_concept(&mut self, concept: &str, related: String) {
-        self.concepts
-            .entry(concept.to_string())
-            .or_default()
-            .related_concepts
-            .insert(related);
+    if !io::stdin().is_terminal() {
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        return check_topic(&input).map_err(FetchError::Config);
     }
 
-    fn add_definition(&mut self, concept: String, definition: String) {
-        self.concepts.entry(concept).or_default().definition = Some(definition);
+    for _ in 0..MAX_TOPIC_ATTEMPTS {
+        print!("What science field(s) are you trying to document? --> ");
+        io::stdout().flush()?;
+
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input)? == 0 {
+            return Err(FetchError::Config("no field given on standard input".to_string()));
+        }
+
+        match check_topic(&input) {
+            Ok(topic) => return Ok(topic),
+            Err(problem) => println!("Please try again: {}.", problem),
+        }
     }
 
-    fn add_example(&mut self, concept: &str, example: String) {
-        self.concepts
-            .entry(concept.to_string())
-            .or_default()
-            .examples
-            .insert(example);
+    Err(FetchError::Config(format!(
+        "no usable field given after {} attempts",
+        MAX_TOPIC_ATTEMPTS
+    )))
+}
+
+fn check_topic(input: &str) -> Result<String, String> {
+    let topic = input.trim();
+    if topic.is_empty() {
+        Err("the field is empty".to_string())
+    } else if topic.chars().count() > MAX_TOPIC_LEN {
+        Err(format!("the field is longer than {} characters", MAX_TOPIC_LEN))
+    } else {
+        Ok(topic.to_string())
     }
 }
 
-const OLLAMA_API_URL: &str = "http://192.168.1.151/api/generate";
-const MODEL: &str = "llama3.1:8b";
+// One topic per line; blank lines and lines starting with '#' are skipped
+fn read_topics_file(path: &Path) -> Result<Vec<String>, FetchError> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut topics = Vec::new();
 
-#[tokio::main]
-async fn main() -> Result<(), reqwest::Error> {
-    let client = Client::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let topic = check_topic(line).map_err(|problem| {
+            FetchError::Config(format!("{} line {}: {}", path.display(), number + 1, problem))
+        })?;
+        topics.push(topic);
+    }
 
-    print!("What science field(s) are you trying to document? --> ");
-    io::stdout().flush().unwrap();
+    if topics.is_empty() {
+        return Err(FetchError::Config(format!("{} lists no topics", path.display())));
+    }
 
-    let mut input = String::new();
-    io::stdin().read_line(&mut input).expect("Failed to read line");
+    Ok(topics)
+}
 
-    let prompt_text = format!(
-        "How does {} relate to other fields of science?",
-        input.trim()
-    );
+// Errors name the entry by position and never echo it, since a value may be
+// a token
+fn parse_headers(entries: &[String]) -> Result<HeaderMap, FetchError> {
+    let mut headers = HeaderMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let invalid = |problem: &str| {
+            FetchError::Config(format!("--header number {} is invalid: {}", i + 1, problem))
+        };
+        let (name, value) = entry.split_once(':').ok_or_else(|| invalid("expected Name: Value"))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| invalid("not a valid header name"))?;
+        let mut value = HeaderValue::from_str(value.trim())
+            .map_err(|_| invalid("not a valid header value"))?;
+        // Gateways often take tokens this way; keep them out of Debug output
+        value.set_sensitive(true);
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+fn validate_sampling(args: &Args) -> Result<(), FetchError> {
+    let temperatures = [args.temperature, Some(args.extraction_temperature)];
+    if temperatures.iter().flatten().any(|t| t.is_nan() || *t < 0.0) {
+        return Err(FetchError::Config("temperature must not be negative".to_string()));
+    }
+    if let Some(top_p) = args.top_p
+        && !(top_p > 0.0 && top_p <= 1.0)
+    {
+        return Err(FetchError::Config("--top-p must be in (0, 1]".to_string()));
+    }
+    Ok(())
+}
 
-    let mut knowledge = Knowledge::default();
+const CONFIG_FILE: &str = ".fetch.toml";
 
-    build_documentation(&mut knowledge, &client, prompt_text).await?;
-    write_documentation_to_file(&knowledge);
+// The command line is parsed once to see what it sets; the config file's
+// values then become the defaults of the options it leaves unset, and the
+// command line is parsed again with them
+fn parse_args() -> Result<Args, FetchError> {
+    let mut command = Args::command();
+    let given = command.clone().get_matches();
+    let path = match given.get_one::<PathBuf>("config") {
+        Some(path) => Some(path.clone()),
+        None => Some(PathBuf::from(CONFIG_FILE)).filter(|path| path.is_file()),
+    };
 
-    Ok(())
+    if let Some(path) = path {
+        let defaults = config_file_defaults(&command, &given, &path)?;
+        check_requirements(&command, &path, &defaults)?;
+        for (id, values) in defaults {
+            command = command.mut_arg(id, |arg| arg.default_values(values));
+        }
+    }
+    let matches = command.get_matches();
+    Ok(Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
 }
 
-async fn get_streamed_text(response: reqwest::Response) -> Result<String, reqwest::Error> {
-    let mut full_text = String::new();
-    let mut buffer = String::new();
-    let mut stream = response.bytes_stream();
+// Reads each `key = value` as the default for the option `--key`: a bool or
+// number is written out, and an array gives one value per element. Entries
+// for an option given on the command line, or one that conflicts with it,
+// are left out.
+fn config_file_defaults(
+    command: &Command,
+    given: &ArgMatches,
+    path: &Path,
+) -> Result<Vec<(Id, Vec<String>)>, FetchError> {
+    let invalid = |problem: String| FetchError::Config(format!("{}: {}", path.display(), problem));
+    let text = std::fs::read_to_string(path)?;
+    let table: toml::Table = toml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
 
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk?;
-        let text = String::from_utf8_lossy(&chunk);
-        buffer.push_str(&text);
+    let on_command_line =
+        |arg: &Arg| given.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine);
+    let conflict = |a: &Arg, b: &Arg| {
+        command.get_arg_conflicts_with(a).contains(&b)
+            || command.get_arg_conflicts_with(b).contains(&a)
+    };
 
-        while let Some(pos) = buffer.find('\n') {
-            let line = buffer[..pos].trim().to_string();
-            buffer = buffer[pos + 1..].to_string();
+    let mut defaults: Vec<(String, &Arg, Vec<String>)> = Vec::new();
+    for (key, value) in table {
+        let flag = key.replace('_', "-");
+        if flag == "topic" || flag == "config" {
+            return Err(invalid(format!("'{}' can only be given on the command line", key)));
+        }
+        let Some(arg) = command.get_arguments().find(|arg| arg.get_long() == Some(&flag)) else {
+            return Err(invalid(format!("'{}' is not an option", key)));
+        };
+        if on_command_line(arg)
+            || command.get_arguments().any(|other| on_command_line(other) && conflict(arg, other))
+        {
+            continue;
+        }
+        if let Some((other, ..)) = defaults.iter().find(|(_, other, _)| conflict(arg, other)) {
+            return Err(invalid(format!("'{}' conflicts with '{}'", key, other)));
+        }
 
-            if line.is_empty() {
-                continue;
-            }
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        let values = values
+            .into_iter()
+            .map(|value| match value {
+                toml::Value::Boolean(b) => Ok(b.to_string()),
+                toml::Value::String(s) => Ok(s),
+                toml::Value::Integer(n) => Ok(n.to_string()),
+                toml::Value::Float(x) => Ok(x.to_string()),
+                _ => Err(invalid(format!("'{}' must be a string, number or boolean", key))),
+            })
+            .collect::<Result<_, _>>()?;
+        defaults.push((key, arg, values));
+    }
+    Ok(defaults
+        .into_iter()
+        .map(|(_, arg, values)| (arg.get_id().clone(), values))
+        .collect())
+}
 
-            match serde_json::from_str::<ResponseChunk>(&line) {
-                Ok(json_chunk) => {
-                    full_text.push_str(&json_chunk.response);
-                    if json_chunk.done {
-                        return Ok(full_text);
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Warning: Failed to parse line as JSON: {}\nError: {}", line, e);
+// clap checks `requires` only for options given explicitly, never for
+// defaults, so the file's entries are tried once as real flags ahead of the
+// command line to catch an option whose requirement is unmet
+fn check_requirements(
+    command: &Command,
+    path: &Path,
+    defaults: &[(Id, Vec<String>)],
+) -> Result<(), FetchError> {
+    let mut argv: Vec<OsString> = std::env::args_os().take(1).collect();
+    for (id, values) in defaults {
+        let Some(arg) = command.get_arguments().find(|arg| arg.get_id() == id) else {
+            continue;
+        };
+        let flag = format!("--{}", arg.get_long().unwrap_or(id.as_str()));
+        for value in values {
+            match arg.get_action() {
+                ArgAction::SetTrue if value == "true" => argv.push(flag.clone().into()),
+                ArgAction::SetTrue => {}
+                ArgAction::Count => {
+                    let times = value.parse().unwrap_or(0);
+                    argv.extend(std::iter::repeat_n(OsString::from(&flag), times));
                 }
+                _ => argv.push(format!("{}={}", flag, value).into()),
             }
         }
     }
+    argv.extend(std::env::args_os().skip(1));
 
-    Ok(full_text)
+    match command.clone().try_get_matches_from(argv) {
+        Err(e) if e.kind() == ErrorKind::MissingRequiredArgument => {
+            let missing = e.get(ContextKind::InvalidArg).map(|names| names.to_string());
+            Err(FetchError::Config(format!(
+                "{}: an option set here also needs {}",
+                path.display(),
+                missing.unwrap_or_else(|| "another option".to_string())
+            )))
+        }
+        // Anything else is reported by the real parse
+        _ => Ok(()),
+    }
 }
 
-async fn build_documentation(
-    knowledge: &mut Knowledge,
-    client: &Client,
-    initial_prompt: String,
-) -> Result<(), reqwest::Error> {
-    knowledge.add_concept("General".to_string());
-
-    // Start with the initial prompt
-    let prompt = Prompt {
-        model: MODEL.to_string(),
-        prompt: initial_prompt.clone(),
+// Flag first, then the environment, then the compiled default. An empty
+// environment variable counts as unset.
+fn resolve_setting(flag: Option<String>, env_key: &str, default: &str) -> String {
+    flag.or_else(|| env_setting(env_key))
+        .unwrap_or_else(|| default.to_string())
+}
+
+fn env_setting(env_key: &str) -> Option<String> {
+    std::env::var(env_key).ok().filter(|v| !v.trim().is_empty())
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), FetchError> {
+    let args = parse_args()?;
+    init_logging(args.verbose);
+    let config = Config::try_from(&args)?;
+    let model = HttpModel {
+        client: build_client(&config)?,
+        config: &config,
     };
 
-    let response = client
-        .post(OLLAMA_API_URL)
-        .json(&prompt)
-        .send()
-        .await?;
+    if args.output.is_some() && args.format.len() > 1 {
+        return Err(FetchError::Config(
+            "--output takes a single format; use --output-dir for several".to_string(),
+        ));
+    }
 
-    let text = get_streamed_text(response).await?;
-    println!("Initial Summary: {}", text);
+    if !args.merge.is_empty() {
+        let output = args.output.as_deref().unwrap_or(Path::new("documentation.json"));
+        return merge_graphs(&args.merge, output, graph_options(&args)?);
+    }
 
-    extract_insights(&text, knowledge, client).await?;
+    if let [old, new] = args.diff.as_slice() {
+        print_diff(&read_knowledge(old)?.diff(&read_knowledge(new)?));
+        return Ok(());
+    }
 
-    // Recursive exploration
-    let mut explored: HashSet<String> = HashSet::new();
-    let mut to_explore: Vec<String> = knowledge
-    .concepts
-    .keys()
-    .filter(|c| *c != "General") // Skip "General"
-    .cloned()
-    .collect();
+    // Checked only where requests are sent, so the offline modes above and a
+    // dry run work whatever the URL
+    let check_api_url = || validate_api_url(&config.api_url, config.api_key.is_some());
 
-    while let Some(concept) = to_explore.pop() {
-        if explored.contains(&concept) {
-            continue;
+    if let Some(path) = &args.extract_file {
+        if args.dry_run {
+            print_extraction_dry_run(&config, &std::fs::read_to_string(path)?);
+            return Ok(());
         }
+        check_api_url()?;
+        return extract_from_file(&args, &model, path).await;
+    }
 
-        explored.insert(concept.clone());
+    if !args.compare_models.is_empty() {
+        if args.compare_models.len() < 2 {
+            return Err(FetchError::Config(
+                "--compare-models needs at least two models".to_string(),
+            ));
+        }
+        let topic = read_topic(args.topic.as_deref())?;
+        if args.dry_run {
+            // Every model is sent the same prompts
+            println!("--- Models ---\n{}\n", args.compare_models.join(", "));
+            print_dry_run(&config, &topic_prompt(&config, &topic));
+            return Ok(());
+        }
+        check_api_url()?;
+        return compare_models(&args, &config, &model.client, &topic).await;
+    }
 
-        let knowledge_depth = to_explore.len();
+    // A query against a resumed graph is a pure lookup: no topic is needed
+    // and the model is never asked
+    if args.query.is_some() && args.resume.is_some() {
+        let mut knowledge = initial_knowledge(&args, &graph_options(&args)?);
+        return report(&mut knowledge, &args, &final_outputs(&args));
+    }
 
-        println!("30 seconds CoolDown starts...");
-        sleep(Duration::from_secs(30)).await;
-        println!("Remaining concepts to explore: {}", knowledge_depth);
-        println!("Exploring related concept: {}", concept);
+    let topics = match &args.topics_file {
+        Some(path) => read_topics_file(path)?,
+        None => vec![read_topic(args.topic.as_deref())?],
+    };
 
-        let prompt_text = format!(
-            "In the context of {}, how does the concept '{}' relate to other scientific disciplines or subfields? List related concepts, define them, and provide examples.",
-            initial_prompt, concept
-        );
+    if args.dry_run {
+        for topic in &topics {
+            let prompt_text = topic_prompt(&config, topic);
+            print_dry_run(&config, &prompt_text);
+        }
+        return Ok(());
+    }
 
-        let prompt = Prompt {
-            model: MODEL.to_string(),
-            prompt: prompt_text,
-        };
+    check_api_url()?;
+    if !args.no_preflight && config.api_format == ApiFormat::Ollama {
+        check_model_installed(&model.client, &config).await?;
+    }
 
-        let response = client
-            .post(OLLAMA_API_URL)
-            .json(&prompt)
-            .send()
-            .await?;
+    spawn_interrupt_handler(config.cancelled.clone());
+    let options = graph_options(&args)?;
+    let mut knowledge = initial_knowledge(&args, &options);
+    // An exploration that fails partway still has its graph written; the
+    // error is returned once that is done
+    let mut failure = None;
 
-        let text = get_streamed_text(response).await?;
-        println!("Summary for '{}': {}", concept, text);
+    for (i, topic) in topics.iter().enumerate() {
+        if config.cancelled.load(Ordering::SeqCst) {
+            break;
+        }
 
-        extract_insights(&text, knowledge, client).await?;
+        if topics.len() > 1 {
+            info!("Topic {} of {}: {}", i + 1, topics.len(), topic);
+        }
+
+        if args.separate_outputs && i > 0 {
+            knowledge = initial_knowledge(&args, &options);
+        }
 
-        if let Some(concept_entry) = knowledge.concepts.get(&concept) {
-            for related in &concept_entry.related_concepts {
-                if !explored.contains(related) && !to_explore.contains(related) {
-                    to_explore.push(related.clone());
+        let prompt_text = topic_prompt(&config, topic);
+        let topic_config = match topics.len() {
+            1 => config.clone(),
+            _ => with_raw_subdir(&config, &topic_slug(topic, i)),
+        };
+        match build_documentation(&mut knowledge, &model, &topic_config, prompt_text).await {
+            Ok(stopped) => {
+                if args.explain {
+                    explain_stop(stopped, &knowledge);
                 }
+                log_cycles(&knowledge);
+            }
+            Err(e) => {
+                warn!("Exploring '{}' failed; writing what was collected", topic);
+                failure = Some(e);
             }
         }
+
+        if args.separate_outputs {
+            let stem = format!("documentation-{}", topic_slug(topic, i));
+            report(&mut knowledge, &args, &output_paths(&args, &stem))?;
+        }
+        if failure.is_some() {
+            break;
+        }
     }
 
-    Ok(())
+    if args.interactive && failure.is_none() {
+        failure = explore_interactively(&mut knowledge, &model, &config, args.explain)
+            .await
+            .err();
+        if failure.is_some() {
+            warn!("Interactive exploration failed; writing what was collected");
+        }
+    }
+
+    if !args.separate_outputs {
+        report(&mut knowledge, &args, &final_outputs(&args))?;
+    }
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
 }
 
+// Skips exploration: the file's text goes straight to the extraction step
+async fn extract_from_file(
+    args: &Args,
+    model: &HttpModel<'_>,
+    path: &Path,
+) -> Result<(), FetchError> {
+    let text = std::fs::read_to_string(path)?;
+    if !args.no_preflight && model.config.api_format == ApiFormat::Ollama {
+        check_model_installed(&model.client, model.config).await?;
+    }
+
+    let options = graph_options(args)?;
+    let mut knowledge = initial_knowledge(args, &options);
+    let source = path.display().to_string();
+    let added = extract_insights(&text, &mut knowledge, model, model.config, &source).await?;
+    info!("Extracted {} insights from {}", added, source);
 
-fn extract_json_block(text: &str) -> Option<String> {
-    let start = text.find('[')?;
-    let end = text.rfind(']')?;
-    Some(text[start..=end].to_string())
+    report(&mut knowledge, args, &final_outputs(args))
 }
 
-async fn extract_insights(
-    text: &str,
-    knowledge: &mut Knowledge,
+// Builds one graph per model from the same topic prompt, then diffs each
+// later graph against the first
+async fn compare_models(
+    args: &Args,
+    config: &Config,
     client: &Client,
-) -> Result<(), reqwest::Error> {
-    let prompt = format!(
-        "Analyze the following text and return JSON with these fields: topic, concept, definition, example.\n\
-        Example JSON format:\n\
-        {{ \"topic\": \"Physics\", \"concept\": \"Gravity\", \"definition\": \"A force...\", \"example\": \"An apple falling...\" }}\n\n\
-        Text: \"{}\"",
-        text
-    );
+    topic: &str,
+) -> Result<(), FetchError> {
+    spawn_interrupt_handler(config.cancelled.clone());
+    let options = graph_options(args)?;
+    let prompt_text = topic_prompt(config, topic);
 
-    let request_body = Prompt {
-        model: MODEL.to_string(),
-        prompt,
-    };
+    let mut graphs = Vec::new();
+    for name in &args.compare_models {
+        let config = Config {
+            model: name.clone(),
+            ..config.clone()
+        };
+        if !args.no_preflight && config.api_format == ApiFormat::Ollama {
+            check_model_installed(client, &config).await?;
+        }
+        let model = HttpModel {
+            client: client.clone(),
+            config: &config,
+        };
 
-    let response = client
-        .post(OLLAMA_API_URL)
-        .json(&request_body)
-        .send()
-        .await?;
+        info!("Exploring with {}", name);
+        let mut knowledge = initial_knowledge(args, &options);
+        let raw_config = with_raw_subdir(&config, &file_safe(name));
+        let explored =
+            build_documentation(&mut knowledge, &model, &raw_config, prompt_text.clone()).await;
+        match &explored {
+            Ok(stopped) if args.explain => explain_stop(*stopped, &knowledge),
+            Ok(_) => {}
+            Err(_) => warn!("Exploring with {} failed; writing what was collected", name),
+        }
+        let stem = format!("documentation-{}", file_safe(name));
+        report(&mut knowledge, args, &output_paths(args, &stem))?;
+        explored?;
+        graphs.push(knowledge);
 
-    let raw_text = get_streamed_text(response).await?;
-    println!("Raw model output:\n{}", raw_text);
+        if config.cancelled.load(Ordering::SeqCst) {
+            break;
+        }
+    }
 
-    match extract_json_block(&raw_text)
-        .and_then(|json| serde_json::from_str::<Vec<StructuredInsight>>(&json).ok())
-    {
-        Some(insights) => {
-            for insight in insights {
-                if let Some(concept) = &insight.concept {
-                    knowledge.add_concept(concept.clone());
-
-                    if let Some(topic) = &insight.topic {
-                        knowledge.add_related_concept(concept, topic.clone());
-                        knowledge.add_related_concept(topic, concept.clone()); // Add reverse link
-                    }
-
-                    if let Some(def) = &insight.definition {
-                        knowledge.add_definition(concept.clone(), def.clone());
-                    }
-
-                    if let Some(ex) = &insight.example {
-                        knowledge.add_example(concept, ex.clone());
-                    }
-                }
+    let (first, rest) = graphs.split_first().expect("at least one model ran");
+    let baseline = &args.compare_models[0];
+    for (name, graph) in args.compare_models[1..].iter().zip(rest) {
+        let diff = first.diff(graph);
+        println!("=== {} (-) vs {} (+) ===", baseline, name);
+        print_diff(&diff);
+        println!(
+            "{}: {} concepts, {}: {} concepts, {} in both",
+            baseline,
+            first.concepts.len(),
+            name,
+            graph.concepts.len(),
+            first.concepts.len() - diff.removed.len()
+        );
+    }
+    Ok(())
+}
+
+// Model names such as llama3.1:8b, made safe for a file name
+fn file_safe(model: &str) -> String {
+    model
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+        .collect()
+}
+
+// Names a topic's output files; a topic with no usable characters falls
+// back to its position
+fn topic_slug(topic: &str, index: usize) -> String {
+    match markdown_anchor(topic) {
+        slug if slug.is_empty() => (index + 1).to_string(),
+        slug => slug,
+    }
+}
+
+// Each exploration in a run that has several keeps its raw summaries in
+// its own subdirectory of --save-raw, so the numbered files don't clash
+fn with_raw_subdir(config: &Config, name: &str) -> Config {
+    Config {
+        save_raw: config.save_raw.as_ref().map(|dir| dir.join(name)),
+        ..config.clone()
+    }
+}
+
+// First Ctrl-C asks the expansion loop to stop so the graph can still be
+// written; a second one exits immediately.
+fn spawn_interrupt_handler(cancelled: Arc<AtomicBool>) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        warn!("Interrupted; stopping after the current request. Press Ctrl-C again to quit now.");
+        cancelled.store(true, Ordering::SeqCst);
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+}
+
+// Reads one follow-up field per line and expands the same graph with it,
+// until end of input or Ctrl-C
+async fn explore_interactively(
+    knowledge: &mut Knowledge,
+    model: &impl ModelClient,
+    config: &Config,
+    explain: bool,
+) -> Result<(), FetchError> {
+    loop {
+        if config.cancelled.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        print!("Next field to explore (Ctrl-D to finish) --> ");
+        io::stdout().flush()?;
+
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input)? == 0 {
+            println!();
+            return Ok(());
+        }
+        if input.trim().is_empty() {
+            continue;
+        }
+        let topic = match check_topic(&input) {
+            Ok(topic) => topic,
+            Err(problem) => {
+                println!("Please try again: {}.", problem);
+                continue;
             }
+        };
+
+        let before: HashSet<String> = knowledge.concepts.keys().cloned().collect();
+        let prompt_text = topic_prompt(config, &topic);
+        let topic_config = with_raw_subdir(config, &topic_slug(&topic, 0));
+        let stopped = build_documentation(knowledge, model, &topic_config, prompt_text).await?;
+        if explain {
+            explain_stop(stopped, knowledge);
         }
-        None => {
-            eprintln!("Failed to parse JSON array or extract it:\n{}", raw_text);
+
+        let mut added: Vec<&str> = knowledge
+            .concepts
+            .keys()
+            .filter(|key| !before.contains(*key))
+            .map(|key| knowledge.display_name(key))
+            .collect();
+        added.sort();
+        println!("Added {} concepts:", added.len());
+        for name in added {
+            println!("  - {}", name);
         }
     }
+}
 
+fn explain_stop(stopped: StopReason, knowledge: &Knowledge) {
+    println!("Stopped: {}; {} concepts collected", stopped, knowledge.concepts.len());
+    match stopped {
+        StopReason::DepthReached { .. } => {
+            println!("  More rounds may find more; raise --depth to continue.")
+        }
+        StopReason::Converged | StopReason::AllExplored => {
+            println!("  The model has run out of new concepts for this topic.")
+        }
+        _ => {}
+    }
+}
+
+// Combines saved graphs without querying the model; `options` decide how
+// conflicting entries are combined
+fn merge_graphs(
+    inputs: &[PathBuf],
+    output: &Path,
+    options: GraphOptions,
+) -> Result<(), FetchError> {
+    let mut merged = Knowledge {
+        options,
+        ..Knowledge::default()
+    };
+    for path in inputs {
+        let knowledge = read_knowledge(path)?;
+        info!("Merging {} concepts from {}", knowledge.concepts.len(), path.display());
+        merged.merge(knowledge);
+    }
+
+    // Written in the format read_knowledge will expect from the extension
+    create_parent_dir(output)?;
+    match output.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml" | "yml") => write_documentation_to_yaml(&merged, output)?,
+        _ => write_json_atomically(&merged, output)?,
+    }
+    info!("Wrote {} concepts to {}", merged.concepts.len(), output.display());
     Ok(())
 }
 
-fn write_documentation_to_file(knowledge: &Knowledge) {
-    use std::io::Write;
-    let mut file = std::fs::File::create("documentation.txt").expect("Failed to create file");
+fn graph_options(args: &Args) -> Result<GraphOptions, FetchError> {
+    let mut aliases = match &args.aliases {
+        Some(path) => read_aliases(path)?,
+        None => HashMap::new(),
+    };
+    if args.case_policy == CasePolicy::Insensitive {
+        aliases = aliases
+            .into_iter()
+            .map(|(alias, canonical)| (alias.to_lowercase(), canonical))
+            .collect();
+    }
 
-    for (concept, details) in &knowledge.concepts {
-        writeln!(file, "Concept: {}", concept).unwrap();
+    Ok(GraphOptions {
+        bidirectional: args.bidirectional,
+        case_policy: args.case_policy,
+        normalize_examples: args.normalize_examples,
+        max_example_len: args.max_example_len,
+        max_concepts: args.max_concepts,
+        max_examples: args.max_examples_per_concept,
+        max_related: args.max_related_per_concept,
+        aliases,
+        track_sources: args.track_sources,
+        single_definition: args.single_definition,
+        exclude: name_filters(&args.exclude_concept),
+        include_only: name_filters(&args.include_only),
+    })
+}
 
-        if let Some(def) = &details.definition {
-            writeln!(file, "  Definition: {}", def).unwrap();
+fn name_filters(patterns: &[String]) -> Vec<String> {
+    patterns
+        .iter()
+        .map(|p| p.trim().to_lowercase())
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+fn initial_knowledge(args: &Args, options: &GraphOptions) -> Knowledge {
+    let mut knowledge = match &args.resume {
+        Some(path) => load_knowledge(path),
+        None => Knowledge::default(),
+    };
+    knowledge.options = options.clone();
+    knowledge
+}
+
+// --output when given, otherwise documentation.<ext> per format
+fn final_outputs(args: &Args) -> Vec<(OutputFormat, PathBuf)> {
+    match &args.output {
+        Some(path) => vec![(output_formats(args)[0], path.clone())],
+        None => output_paths(args, "documentation"),
+    }
+}
+
+fn output_formats(args: &Args) -> Vec<OutputFormat> {
+    match (args.format.is_empty(), &args.output_dir) {
+        (false, _) => args.format.clone(),
+        (true, Some(_)) => vec![OutputFormat::Text, OutputFormat::Json, OutputFormat::Dot],
+        (true, None) => vec![OutputFormat::Text],
+    }
+}
+
+// <stem>.<ext> for each chosen format, inside --output-dir when given
+fn output_paths(args: &Args, stem: &str) -> Vec<(OutputFormat, PathBuf)> {
+    let dir = args.output_dir.clone().unwrap_or_default();
+    output_formats(args)
+        .into_iter()
+        .map(|format| (format, dir.join(format!("{}.{}", stem, format.extension()))))
+        .collect()
+}
+
+// Prints the requested summaries, then writes the documentation to each of
+// `outputs` unless a query replaces it
+fn report(
+    knowledge: &mut Knowledge,
+    args: &Args,
+    outputs: &[(OutputFormat, PathBuf)],
+) -> Result<(), FetchError> {
+    if args.normalize {
+        let folded = knowledge.normalize();
+        info!("Folded {} concepts into others with the same name", folded);
+    }
+    if let Some(min) = args.min_confidence {
+        let dropped = knowledge.filter_by_confidence(min);
+        info!("Dropped {} definitions and examples scored below {}", dropped, min);
+    }
+    if args.min_content {
+        let pruned = knowledge.prune();
+        info!("Pruned {} concepts with no content", pruned);
+    }
+    if let Some(focus) = &args.focus {
+        *knowledge = knowledge.neighborhood(focus, args.radius).ok_or_else(|| {
+            FetchError::Config(format!("no concept named '{}' in the graph", focus))
+        })?;
+        info!(
+            "Kept {} concepts within {} hops of '{}'",
+            knowledge.concepts.len(),
+            args.radius,
+            focus
+        );
+    }
+    let knowledge = &*knowledge;
+
+    if let Some(n) = args.show_top {
+        print_top_concepts(knowledge, n);
+    }
+
+    if let Some(term) = &args.query {
+        print_search_results(knowledge, term);
+        return Ok(());
+    }
+
+    log_stats(knowledge);
+
+    // Every output is attempted, so one unwritable path doesn't cost the rest
+    let mut written = Vec::new();
+    let mut failed = Vec::new();
+    let mut record = |path: &Path, result: Result<(), FetchError>| match result {
+        Ok(()) => written.push(path.display().to_string()),
+        Err(e) => {
+            warn!("Failed to write {}: {}", path.display(), e);
+            failed.push(path.display().to_string());
+        }
+    };
+    if let Some(stats_path) = &args.stats_json {
+        record(stats_path, write_stats(knowledge, stats_path));
+    }
+    for (format, path) in outputs {
+        record(path, write_output(knowledge, *format, path, args.append));
+    }
+    let attempted = outputs.len() + usize::from(args.stats_json.is_some());
+    if !written.is_empty() {
+        info!("Wrote {}", written.join(", "));
+    }
+
+    if !failed.is_empty() {
+        return Err(FetchError::Io(io::Error::other(format!(
+            "{} of {} outputs could not be written: {}",
+            failed.len(),
+            attempted,
+            failed.join(", ")
+        ))));
+    }
+    Ok(())
+}
+
+fn write_stats(knowledge: &Knowledge, path: &Path) -> Result<(), FetchError> {
+    create_parent_dir(path)?;
+    let file = std::fs::File::create(path)?;
+    serde_json::to_writer_pretty(file, &knowledge.stats())?;
+    Ok(())
+}
+
+fn write_output(
+    knowledge: &Knowledge,
+    format: OutputFormat,
+    path: &Path,
+    append: bool,
+) -> Result<(), FetchError> {
+    create_parent_dir(path)?;
+    match format {
+        OutputFormat::Text => write_documentation_to_file(knowledge, path, append),
+        OutputFormat::Dot => write_documentation_to_dot(knowledge, path),
+        OutputFormat::Json => write_documentation_to_json(knowledge, path),
+        OutputFormat::Markdown => write_documentation_to_markdown(knowledge, path),
+        OutputFormat::Csv => write_documentation_to_csv(knowledge, path),
+        OutputFormat::Yaml => write_documentation_to_yaml(knowledge, path),
+        OutputFormat::Gexf => write_documentation_to_gexf(knowledge, path),
+        OutputFormat::Html => write_documentation_to_html(knowledge, path),
+    }
+}
+
+fn print_top_concepts(knowledge: &Knowledge, n: usize) {
+    println!("Most connected concepts:");
+    for (rank, (concept, degree)) in knowledge.rank_by_connectivity().iter().take(n).enumerate() {
+        println!("{:>3}. {} ({})", rank + 1, concept, degree);
+    }
+}
+
+fn print_diff(diff: &GraphDiff) {
+    if diff.is_empty() {
+        println!("No differences.");
+        return;
+    }
+
+    for name in &diff.added {
+        println!("+ {}", name);
+    }
+    for name in &diff.removed {
+        println!("- {}", name);
+    }
+
+    for change in &diff.changed {
+        println!("~ {}", change.concept);
+
+        for def in &change.definitions_added {
+            println!("    + Definition: {}", def);
+        }
+        for def in &change.definitions_removed {
+            println!("    - Definition: {}", def);
+        }
+        for example in &change.examples_added {
+            println!("    + Example: {}", example);
+        }
+        for example in &change.examples_removed {
+            println!("    - Example: {}", example);
+        }
+        for rc in &change.related_added {
+            println!("    + Related: {}", rc);
+        }
+        for rc in &change.related_removed {
+            println!("    - Related: {}", rc);
+        }
+    }
+
+    println!(
+        "{} added, {} removed, {} changed",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len()
+    );
+}
+
+fn print_search_results(knowledge: &Knowledge, term: &str) {
+    let results = knowledge.search(term);
+    if results.is_empty() {
+        println!("No concepts match '{}'.", term);
+        return;
+    }
+
+    for (concept, details) in results {
+        println!("Concept: {}", knowledge.display_name(concept));
+
+        for def in &details.definitions {
+            println!("  Definition: {}", def);
         }
 
         if !details.examples.is_empty() {
-            writeln!(file, "  Examples:").unwrap();
-            for example in &details.examples {
-                writeln!(file, "    - {}", example).unwrap();
+            println!("  Examples:");
+            let mut examples: Vec<&String> = details.examples.iter().collect();
+            examples.sort();
+            for example in examples {
+                println!("    - {}", example);
             }
         }
 
         if !details.related_concepts.is_empty() {
-            writeln!(file, "  Related Concepts:").unwrap();
-            for rc in &details.related_concepts {
-                writeln!(file, "    - {}", rc).unwrap();
+            println!("  Related Concepts:");
+            for (rc, relation) in knowledge.sorted_related(details) {
+                println!("    - {}", relation_label(rc, relation));
             }
         }
 
-        writeln!(file).unwrap();
+        println!();
     }
 }
+
+fn log_stats(knowledge: &Knowledge) {
+    let stats = knowledge.stats();
+    info!(
+        "Graph: {} concepts ({} without a definition), {} examples, {} relations ({:.2} per concept)",
+        stats.concepts,
+        stats.without_definition,
+        stats.examples,
+        stats.relations,
+        stats.relations_per_concept
+    );
+}
+
+fn log_cycles(knowledge: &Knowledge) {
+    let cycles = knowledge.find_cycles();
+    if cycles.is_empty() {
+        info!("No cycles in the concept graph.");
+        return;
+    }
+
+    info!("Found {} cycles in the concept graph:", cycles.len());
+    for cycle in cycles.iter().take(10) {
+        let mut names: Vec<&str> = cycle.iter().map(|c| knowledge.display_name(c)).collect();
+        names.push(names[0]);
+        info!("  {}", names.join(" -> "));
+    }
+    if cycles.len() > 10 {
+        info!("  ... and {} more", cycles.len() - 10);
+    }
+}
+
+// Shows each kind of prompt a real run would send, with placeholders where
+// the text depends on earlier model output.
+fn print_dry_run(config: &Config, initial_prompt: &str) {
+    println!("--- Initial prompt ---\n{}\n", initial_prompt);
+    println!(
+        "--- Extraction prompt ---\n{}\n",
+        extraction_prompt("<model response to the prompt above>")
+    );
+    if config.depth > 0 {
+        println!(
+            "--- Expansion prompt (round 1 of {}, once per concept) ---\n{}",
+            config.depth,
+            expansion_prompt(config, initial_prompt, "<concept>")
+        );
+    }
+}
+
+// --extract-file sends one extraction prompt per piece of the file
+fn print_extraction_dry_run(config: &Config, text: &str) {
+    let chunks = match config.extract_chunk_size {
+        Some(size) => chunk_text(text, size),
+        None => vec![text],
+    };
+    for (i, chunk) in chunks.iter().enumerate() {
+        match chunks.len() {
+            1 => println!("--- Extraction prompt ---"),
+            n => println!("--- Extraction prompt (piece {} of {}) ---", i + 1, n),
+        }
+        println!("{}\n", extraction_prompt(chunk));
+    }
+}
+
+fn init_logging(verbose: u8) {
+    let level = match verbose {
+        0 => tracing::Level::INFO,
+        1 => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE,
+    };
+
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_target(false)
+        .with_writer(io::stderr)
+        .init();
+}
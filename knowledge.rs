@@ -0,0 +1,1085 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, warn};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StructuredInsight {
+    pub topic: Option<String>,
+    pub concept: Option<String>,
+    pub definition: Option<String>,
+    pub example: Option<String>,
+    // How the concept relates to the topic, e.g. "is-a" or "part-of"
+    pub relation: Option<String>,
+    // How sure the model is, 0.0 to 1.0; anything unreadable counts as unknown
+    #[serde(default, deserialize_with = "lenient_score")]
+    pub confidence: Option<f64>,
+}
+
+// The same fields, but an unknown key is an error (--strict-json)
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StrictInsight {
+    topic: Option<String>,
+    concept: Option<String>,
+    definition: Option<String>,
+    example: Option<String>,
+    relation: Option<String>,
+    #[serde(default, deserialize_with = "lenient_score")]
+    confidence: Option<f64>,
+}
+
+impl From<StrictInsight> for StructuredInsight {
+    fn from(strict: StrictInsight) -> Self {
+        StructuredInsight {
+            topic: strict.topic,
+            concept: strict.concept,
+            definition: strict.definition,
+            example: strict.example,
+            relation: strict.relation,
+            confidence: strict.confidence,
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "StoredConcept")]
+pub struct Concept {
+    // Every distinct definition, in the order they were first given
+    pub definitions: Vec<String>,
+    // Confidence per definition, for the definitions the model scored
+    #[serde(skip_serializing_if = "HashMap::is_empty", serialize_with = "serialize_sorted_map")]
+    pub definition_confidence: HashMap<String, f64>,
+    #[serde(serialize_with = "serialize_sorted_set")]
+    pub examples: HashSet<String>,
+    // Confidence per example, for the examples the model scored
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "serialize_sorted_map"
+    )]
+    pub example_confidence: HashMap<String, f64>,
+    // Target -> relation type
+    #[serde(serialize_with = "serialize_sorted_map")]
+    pub related_concepts: HashMap<String, String>,
+    // Exploration prompts whose answers mentioned the concept (--track-sources)
+    #[serde(
+        default,
+        skip_serializing_if = "HashSet::is_empty",
+        serialize_with = "serialize_sorted_set"
+    )]
+    pub source_prompts: HashSet<String>,
+}
+
+impl Concept {
+    pub fn has_definition(&self) -> bool {
+        !self.definitions.is_empty()
+    }
+
+    // Adds a definition unless it is already held; with `single` it
+    // replaces the ones held instead (--single-definition)
+    fn push_definition(&mut self, definition: String, confidence: Option<f64>, single: bool) {
+        if definition.trim().is_empty() {
+            return;
+        }
+        if single {
+            self.definitions.clear();
+            self.definition_confidence.clear();
+        }
+        if let Some(score) = confidence {
+            self.definition_confidence.insert(definition.clone(), score);
+        }
+        if !self.definitions.contains(&definition) {
+            self.definitions.push(definition);
+        }
+    }
+
+    // Takes over the definitions this concept lacks, with their scores
+    fn union_definitions(&mut self, definitions: Vec<String>, scores: &HashMap<String, f64>) {
+        for definition in definitions {
+            if !self.definitions.contains(&definition) {
+                let score = scores.get(&definition).copied();
+                self.push_definition(definition, score, false);
+            }
+        }
+    }
+}
+
+// How a concept is read back. Graphs saved before definitions were kept
+// side by side hold a single `definition` with one score, and graphs saved
+// before relations were typed list only targets, which load as RELATED.
+#[derive(Deserialize)]
+struct StoredConcept {
+    #[serde(default, alias = "definition")]
+    definitions: StoredDefinitions,
+    #[serde(default)]
+    definition_confidence: StoredScores,
+    examples: HashSet<String>,
+    #[serde(default)]
+    example_confidence: HashMap<String, f64>,
+    #[serde(deserialize_with = "lenient_relations")]
+    related_concepts: HashMap<String, String>,
+    #[serde(default)]
+    source_prompts: HashSet<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredDefinitions {
+    Many(Vec<String>),
+    One(Option<String>),
+}
+
+impl Default for StoredDefinitions {
+    fn default() -> Self {
+        StoredDefinitions::One(None)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredScores {
+    PerDefinition(HashMap<String, f64>),
+    One(Option<f64>),
+}
+
+impl Default for StoredScores {
+    fn default() -> Self {
+        StoredScores::One(None)
+    }
+}
+
+impl From<StoredConcept> for Concept {
+    fn from(stored: StoredConcept) -> Self {
+        let definitions: Vec<String> = match stored.definitions {
+            StoredDefinitions::Many(definitions) => definitions,
+            StoredDefinitions::One(definition) => definition.into_iter().collect(),
+        };
+        let definitions: Vec<String> =
+            definitions.into_iter().filter(|d| !d.trim().is_empty()).collect();
+        let definition_confidence = match stored.definition_confidence {
+            StoredScores::PerDefinition(scores) => scores,
+            StoredScores::One(score) => match (definitions.first(), score) {
+                (Some(definition), Some(score)) => HashMap::from([(definition.clone(), score)]),
+                _ => HashMap::new(),
+            },
+        };
+
+        Concept {
+            definitions,
+            definition_confidence,
+            examples: stored.examples,
+            example_confidence: stored.example_confidence,
+            related_concepts: stored.related_concepts,
+            source_prompts: stored.source_prompts,
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct Knowledge {
+    #[serde(serialize_with = "serialize_sorted_map")]
+    pub concepts: HashMap<String, Concept>,
+    // Original spelling for keys that were normalized
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "serialize_sorted_map"
+    )]
+    pub display_names: HashMap<String, String>,
+    // Concepts whose expansion prompt has already been answered, so a
+    // resumed run does not ask about them again
+    #[serde(
+        default,
+        skip_serializing_if = "HashSet::is_empty",
+        serialize_with = "serialize_sorted_set"
+    )]
+    pub expanded: HashSet<String>,
+    // Digests of the texts already extracted, so the same summary is not
+    // extracted twice, even across resumed runs
+    #[serde(
+        default,
+        skip_serializing_if = "HashSet::is_empty",
+        serialize_with = "serialize_sorted_set"
+    )]
+    pub extracted_texts: HashSet<String>,
+    #[serde(skip)]
+    pub options: GraphOptions,
+}
+
+// Size and coverage figures for comparing runs
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct GraphStats {
+    pub concepts: usize,
+    pub without_definition: usize,
+    pub examples: usize,
+    pub relations: usize,
+    pub relations_per_concept: f64,
+}
+
+// What changed between two graphs, by display name and sorted
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GraphDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<ConceptDiff>,
+}
+
+impl GraphDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ConceptDiff {
+    pub concept: String,
+    pub definitions_added: Vec<String>,
+    pub definitions_removed: Vec<String>,
+    pub examples_added: Vec<String>,
+    pub examples_removed: Vec<String>,
+    pub related_added: Vec<String>,
+    pub related_removed: Vec<String>,
+}
+
+impl ConceptDiff {
+    fn is_empty(&self) -> bool {
+        self.definitions_added.is_empty()
+            && self.definitions_removed.is_empty()
+            && self.examples_added.is_empty()
+            && self.examples_removed.is_empty()
+            && self.related_added.is_empty()
+            && self.related_removed.is_empty()
+    }
+}
+
+// How the add_* methods shape the graph. Not part of the saved output.
+#[derive(Default, Clone, Debug)]
+pub struct GraphOptions {
+    pub bidirectional: bool,
+    pub case_policy: CasePolicy,
+    pub normalize_examples: bool,
+    pub max_example_len: Option<usize>,
+    pub max_concepts: Option<usize>,
+    pub max_examples: Option<usize>,
+    pub max_related: Option<usize>,
+    // Alias -> canonical name; keys are lowercased under the insensitive
+    // policy
+    pub aliases: HashMap<String, String>,
+    pub track_sources: bool,
+    pub single_definition: bool,
+    // Lowercased name fragments; a concept matching any exclude, or none of
+    // a non-empty include list, is kept out of the graph
+    pub exclude: Vec<String>,
+    pub include_only: Vec<String>,
+}
+
+impl GraphOptions {
+    pub fn canonical_name<'a>(&'a self, name: &'a str) -> &'a str {
+        let found = match self.case_policy {
+            CasePolicy::Sensitive => self.aliases.get(name),
+            CasePolicy::Insensitive => self.aliases.get(&name.to_lowercase()),
+        };
+        found.map_or(name, |canonical| canonical.trim())
+    }
+
+    pub fn allows(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        let matches = |patterns: &[String]| patterns.iter().any(|p| name.contains(p.as_str()));
+        !matches(&self.exclude) && (self.include_only.is_empty() || matches(&self.include_only))
+    }
+
+    // Lowercasing and collapsing whitespace lets near-identical examples land
+    // on the same set entry; the length cap is applied after that.
+    pub fn example_key(&self, example: String) -> String {
+        let example = if self.normalize_examples {
+            example
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase()
+        } else {
+            example
+        };
+
+        match self.max_example_len {
+            Some(max) if example.chars().count() > max => {
+                let mut capped: String = example.chars().take(max).collect();
+                capped.truncate(capped.trim_end().len());
+                capped.push('…');
+                capped
+            }
+            _ => example,
+        }
+    }
+}
+
+#[derive(ValueEnum, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CasePolicy {
+    /// Keep concept names as written apart from trimming whitespace
+    #[default]
+    Sensitive,
+    /// Treat names differing only in case as the same concept
+    Insensitive,
+}
+
+// Accepts a number or a numeric string in 0..=1; anything else is None so a
+// sloppy score never costs the rest of the insight
+fn lenient_score<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<f64>, D::Error> {
+    let value = Option::<serde_json::Value>::deserialize(deserializer)?;
+    let score = match value {
+        Some(serde_json::Value::Number(n)) => n.as_f64(),
+        Some(serde_json::Value::String(s)) => s.trim().parse().ok(),
+        _ => None,
+    };
+    Ok(score.filter(|s: &f64| (0.0..=1.0).contains(s)))
+}
+
+// The relation type used when the model gives none
+pub const RELATED: &str = "related";
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Relations {
+    Typed(HashMap<String, String>),
+    Untyped(Vec<String>),
+}
+
+fn lenient_relations<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, String>, D::Error> {
+    Ok(match Relations::deserialize(deserializer)? {
+        Relations::Typed(map) => map,
+        Relations::Untyped(targets) => {
+            targets.into_iter().map(|t| (t, RELATED.to_string())).collect()
+        }
+    })
+}
+
+// Records a relation, letting a specific type replace the generic one but
+// never the other way round. Once `max` targets are held, new ones are
+// dropped.
+fn set_relation(
+    relations: &mut HashMap<String, String>,
+    target: String,
+    relation: &str,
+    max: Option<usize>,
+) {
+    match relations.get(&target) {
+        Some(existing) if existing != RELATED => {}
+        Some(_) => {
+            relations.insert(target, relation.to_string());
+        }
+        None if max.is_some_and(|max| relations.len() >= max) => {}
+        None => {
+            relations.insert(target, relation.to_string());
+        }
+    }
+}
+
+// A full example set keeps its longest entries: the new example replaces
+// the shortest one if it is longer. Returns whether there is room now.
+fn make_room(entry: &mut Concept, example: &str) -> bool {
+    let len = example.chars().count();
+    let shortest = entry
+        .examples
+        .iter()
+        .min_by_key(|e| (e.chars().count(), e.as_str()))
+        .filter(|e| e.chars().count() < len)
+        .cloned();
+    match shortest {
+        Some(shortest) => {
+            entry.examples.remove(&shortest);
+            entry.example_confidence.remove(&shortest);
+            true
+        }
+        None => false,
+    }
+}
+
+// Sets and maps are written in sorted order so the JSON output diffs cleanly.
+fn serialize_sorted_set<S: serde::Serializer>(
+    set: &HashSet<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut items: Vec<&String> = set.iter().collect();
+    items.sort();
+    items.serialize(serializer)
+}
+
+fn serialize_sorted_map<S: serde::Serializer, V: Serialize>(
+    map: &HashMap<String, V>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    map.iter()
+        .collect::<std::collections::BTreeMap<_, _>>()
+        .serialize(serializer)
+}
+
+impl Knowledge {
+    // Map key for a concept name. Names are always trimmed and aliases are
+    // resolved to their canonical name; under the insensitive policy the key
+    // is lowercased and the first spelling seen is kept for display.
+    pub fn concept_key(&mut self, name: &str) -> String {
+        let key = self.lookup_key(name);
+        if self.options.case_policy == CasePolicy::Insensitive {
+            let trimmed = self.options.canonical_name(name.trim()).to_string();
+            self.display_names.entry(key.clone()).or_insert(trimmed);
+        }
+        key
+    }
+
+    // The key a name maps to, without recording its display spelling
+    fn lookup_key(&self, name: &str) -> String {
+        let trimmed = self.options.canonical_name(name.trim());
+        match self.options.case_policy {
+            CasePolicy::Sensitive => trimmed.to_string(),
+            CasePolicy::Insensitive => trimmed.to_lowercase(),
+        }
+    }
+
+    pub fn display_name<'a>(&'a self, key: &'a str) -> &'a str {
+        self.display_names.get(key).map_or(key, String::as_str)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.options
+            .max_concepts
+            .is_some_and(|max| self.concepts.len() >= max)
+    }
+
+    // Existing concepts are always returned; new ones only while under the
+    // --max-concepts cap.
+    fn concept_entry(&mut self, key: String) -> Option<&mut Concept> {
+        if !self.concepts.contains_key(&key) && self.is_full() {
+            return None;
+        }
+        Some(self.concepts.entry(key).or_default())
+    }
+
+    pub fn add_concept(&mut self, concept: String) {
+        let key = self.concept_key(&concept);
+        self.concept_entry(key);
+    }
+
+    pub fn add_related_concept(&mut self, concept: &str, related: String, relation: Option<&str>) {
+        let key = self.concept_key(concept);
+        let related = self.concept_key(&related);
+        if related == key {
+            debug!("Dropping self-relation on '{}'", self.display_name(&key));
+            return;
+        }
+        let relation = relation
+            .map(|r| r.trim().to_lowercase())
+            .filter(|r| !r.is_empty())
+            .unwrap_or_else(|| RELATED.to_string());
+
+        let max = self.options.max_related;
+        if let Some(entry) = self.concept_entry(key.clone()) {
+            set_relation(&mut entry.related_concepts, related.clone(), &relation, max);
+        }
+
+        if self.options.bidirectional {
+            // A type read one way rarely holds the other way, so the reverse
+            // edge stays generic
+            if let Some(entry) = self.concept_entry(related) {
+                set_relation(&mut entry.related_concepts, key, RELATED, max);
+            }
+        }
+    }
+
+    pub fn add_definition(
+        &mut self,
+        concept: String,
+        definition: String,
+        confidence: Option<f64>,
+    ) {
+        let key = self.concept_key(&concept);
+        let single = self.options.single_definition;
+        if let Some(entry) = self.concept_entry(key) {
+            entry.push_definition(definition, confidence, single);
+        }
+    }
+
+    pub fn add_example(&mut self, concept: &str, example: String, confidence: Option<f64>) {
+        let key = self.concept_key(concept);
+        let example = self.options.example_key(example);
+        let max = self.options.max_examples;
+        if let Some(entry) = self.concept_entry(key) {
+            if let Some(max) = max
+                && !entry.examples.contains(&example)
+                && entry.examples.len() >= max
+                && !make_room(entry, &example)
+            {
+                return;
+            }
+            if let Some(score) = confidence {
+                entry.example_confidence.insert(example.clone(), score);
+            }
+            entry.examples.insert(example);
+        }
+    }
+
+    // Drops definitions and examples scored below `min`; unscored ones stay.
+    // Returns how many were dropped.
+    pub fn filter_by_confidence(&mut self, min: f64) -> usize {
+        let mut dropped = 0;
+        for concept in self.concepts.values_mut() {
+            let low: Vec<String> = concept
+                .definition_confidence
+                .iter()
+                .filter(|(_, c)| **c < min)
+                .map(|(definition, _)| definition.clone())
+                .collect();
+            for definition in low {
+                concept.definitions.retain(|d| *d != definition);
+                concept.definition_confidence.remove(&definition);
+                dropped += 1;
+            }
+
+            let low: Vec<String> = concept
+                .example_confidence
+                .iter()
+                .filter(|(_, c)| **c < min)
+                .map(|(example, _)| example.clone())
+                .collect();
+            for example in low {
+                concept.examples.remove(&example);
+                concept.example_confidence.remove(&example);
+                dropped += 1;
+            }
+        }
+        dropped
+    }
+
+    // Unions another graph into this one. With --single-definition the
+    // first definition wins and a differing one from `other` is logged and
+    // dropped; otherwise both are kept.
+    pub fn merge(&mut self, other: Knowledge) {
+        for (key, display) in other.display_names {
+            self.display_names.entry(key).or_insert(display);
+        }
+        self.expanded.extend(other.expanded);
+        self.extracted_texts.extend(other.extracted_texts);
+
+        for (key, incoming) in other.concepts {
+            let entry = self.concepts.entry(key.clone()).or_default();
+            match (entry.definitions.first(), incoming.definitions.first()) {
+                (Some(current), Some(def)) if self.options.single_definition => {
+                    if current != def {
+                        warn!(
+                            "Conflicting definitions for '{}'; keeping \"{}\" over \"{}\"",
+                            key, current, def
+                        );
+                    }
+                }
+                _ => entry
+                    .union_definitions(incoming.definitions, &incoming.definition_confidence),
+            }
+            entry.examples.extend(incoming.examples);
+            for (example, score) in incoming.example_confidence {
+                entry.example_confidence.entry(example).or_insert(score);
+            }
+            for (target, relation) in incoming.related_concepts {
+                set_relation(&mut entry.related_concepts, target, &relation, None);
+            }
+            entry.source_prompts.extend(incoming.source_prompts);
+        }
+    }
+
+    // Concepts in a fixed order for the writers: by display name ignoring
+    // case, then by key, so the same graph always produces the same file
+    pub fn sorted_concepts(&self) -> Vec<(&String, &Concept)> {
+        let mut concepts: Vec<(&String, &Concept)> = self.concepts.iter().collect();
+        concepts.sort_by(|(a, _), (b, _)| {
+            let a_name = self.display_name(a).to_lowercase();
+            let b_name = self.display_name(b).to_lowercase();
+            a_name.cmp(&b_name).then_with(|| a.cmp(b))
+        });
+        concepts
+    }
+
+    // (name, relation type) pairs in display order
+    pub fn sorted_related<'a>(&'a self, concept: &'a Concept) -> Vec<(&'a str, &'a str)> {
+        let mut related: Vec<(&str, &str)> = concept
+            .related_concepts
+            .iter()
+            .map(|(rc, relation)| (self.display_name(rc), relation.as_str()))
+            .collect();
+        related.sort();
+        related
+    }
+
+    // The concepts within `radius` hops of `concept`, following relations in
+    // either direction since most of them point from a concept to its topic.
+    // Relations leaving the neighbourhood are dropped. None when the concept
+    // is not in the graph.
+    pub fn neighborhood(&self, concept: &str, radius: usize) -> Option<Knowledge> {
+        let start = self.lookup_key(concept);
+        if !self.concepts.contains_key(&start) {
+            return None;
+        }
+
+        let mut neighbors: HashMap<&str, Vec<&str>> = HashMap::new();
+        for (key, details) in &self.concepts {
+            for rc in details.related_concepts.keys() {
+                neighbors.entry(key).or_default().push(rc);
+                neighbors.entry(rc).or_default().push(key);
+            }
+        }
+
+        let mut reached: HashSet<&str> = HashSet::from([start.as_str()]);
+        let mut frontier = vec![start.as_str()];
+        for _ in 0..radius {
+            let mut next = Vec::new();
+            for key in frontier {
+                for neighbor in neighbors.get(key).into_iter().flatten() {
+                    if reached.insert(neighbor) {
+                        next.push(*neighbor);
+                    }
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            frontier = next;
+        }
+
+        let mut subgraph = Knowledge {
+            options: self.options.clone(),
+            ..Default::default()
+        };
+        for key in &reached {
+            if let Some(details) = self.concepts.get(*key) {
+                let mut details = details.clone();
+                details.related_concepts.retain(|rc, _| reached.contains(rc.as_str()));
+                subgraph.concepts.insert(key.to_string(), details);
+            }
+            if let Some(name) = self.display_names.get(*key) {
+                subgraph.display_names.insert(key.to_string(), name.clone());
+            }
+            if self.expanded.contains(*key) {
+                subgraph.expanded.insert(key.to_string());
+            }
+        }
+        Some(subgraph)
+    }
+
+    // Folds together concepts whose names differ only by case or spacing,
+    // whichever policy the graph was built with. The lowest key of each
+    // group names the result; sets and definitions are unioned, or the
+    // longer definition wins with --single-definition. Returns how many
+    // concepts were folded away.
+    pub fn normalize(&mut self) -> usize {
+        let collapse = |name: &str| name.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        let mut names: Vec<&String> = self
+            .concepts
+            .iter()
+            .flat_map(|(key, c)| std::iter::once(key).chain(c.related_concepts.keys()))
+            .collect();
+        names.sort();
+        names.dedup();
+
+        // Every name maps to the key of the first name in its group
+        let mut groups: HashMap<String, String> = HashMap::new();
+        let mut rename: HashMap<String, String> = HashMap::new();
+        let mut display_names = HashMap::new();
+        for name in names {
+            let display = collapse(self.display_name(name));
+            let new_key = groups.entry(display.to_lowercase()).or_insert_with(|| {
+                let new_key = match self.options.case_policy {
+                    CasePolicy::Sensitive => display.clone(),
+                    CasePolicy::Insensitive => display.to_lowercase(),
+                };
+                if new_key != display {
+                    display_names.insert(new_key.clone(), display.clone());
+                }
+                new_key
+            });
+            rename.insert(name.clone(), new_key.clone());
+        }
+
+        let before = self.concepts.len();
+        let mut concepts: HashMap<String, Concept> = HashMap::new();
+        for (key, incoming) in std::mem::take(&mut self.concepts) {
+            let new_key = rename[&key].clone();
+            let entry = concepts.entry(new_key.clone()).or_default();
+
+            if self.options.single_definition {
+                let current_len = entry.definitions.first().map_or(0, |d| d.chars().count());
+                if let Some(def) = incoming.definitions.first()
+                    && def.chars().count() > current_len
+                {
+                    let score = incoming.definition_confidence.get(def).copied();
+                    entry.push_definition(def.clone(), score, true);
+                }
+            } else {
+                entry.union_definitions(incoming.definitions, &incoming.definition_confidence);
+            }
+            entry.examples.extend(incoming.examples);
+            for (example, score) in incoming.example_confidence {
+                entry.example_confidence.entry(example).or_insert(score);
+            }
+            for (rc, relation) in &incoming.related_concepts {
+                let target = rename[rc].clone();
+                if target != new_key {
+                    set_relation(&mut entry.related_concepts, target, relation, None);
+                }
+            }
+            entry.source_prompts.extend(incoming.source_prompts);
+        }
+
+        self.concepts = concepts;
+        self.display_names = display_names;
+        self.expanded = std::mem::take(&mut self.expanded)
+            .into_iter()
+            .map(|key| rename.get(&key).cloned().unwrap_or(key))
+            .collect();
+        before - self.concepts.len()
+    }
+
+    // Removes concepts that carry nothing but a name and returns how many
+    // went. Other concepts may still list them as related.
+    pub fn prune(&mut self) -> usize {
+        let before = self.concepts.len();
+        self.concepts.retain(|_, c| {
+            c.has_definition()
+                || !c.examples.is_empty()
+                || !c.related_concepts.is_empty()
+        });
+        before - self.concepts.len()
+    }
+
+    pub fn stats(&self) -> GraphStats {
+        let concepts = self.concepts.len();
+        let relations = self.concepts.values().map(|c| c.related_concepts.len()).sum();
+        GraphStats {
+            concepts,
+            without_definition: self
+                .concepts
+                .values()
+                .filter(|c| !c.has_definition())
+                .count(),
+            examples: self.concepts.values().map(|c| c.examples.len()).sum(),
+            relations,
+            relations_per_concept: if concepts == 0 {
+                0.0
+            } else {
+                relations as f64 / concepts as f64
+            },
+        }
+    }
+
+    // Compares this (older) graph with a newer one, matching concepts by key.
+    pub fn diff(&self, other: &Knowledge) -> GraphDiff {
+        let sorted_names = |graph: &Knowledge, keys: Vec<&String>| {
+            let mut names: Vec<String> =
+                keys.into_iter().map(|k| graph.display_name(k).to_string()).collect();
+            names.sort_by_key(|name| name.to_lowercase());
+            names
+        };
+        // A relation whose type changed shows up as removed and added
+        let only_in =
+            |a: &HashMap<String, String>, b: &HashMap<String, String>, graph: &Knowledge| {
+                let mut names: Vec<String> = a
+                    .iter()
+                    .filter(|(target, relation)| b.get(*target) != Some(relation))
+                    .map(|(target, relation)| {
+                        format!("{} ({})", graph.display_name(target), relation)
+                    })
+                    .collect();
+                names.sort_by_key(|name| name.to_lowercase());
+                names
+            };
+        let only_new = |a: &Vec<String>, b: &Vec<String>| {
+            a.iter().filter(|d| !b.contains(d)).cloned().collect::<Vec<String>>()
+        };
+        let sorted_examples = |a: &HashSet<String>, b: &HashSet<String>| {
+            let mut examples: Vec<String> = a.difference(b).cloned().collect();
+            examples.sort();
+            examples
+        };
+
+        let mut diff = GraphDiff {
+            added: sorted_names(
+                other,
+                other.concepts.keys().filter(|k| !self.concepts.contains_key(*k)).collect(),
+            ),
+            removed: sorted_names(
+                self,
+                self.concepts.keys().filter(|k| !other.concepts.contains_key(*k)).collect(),
+            ),
+            changed: Vec::new(),
+        };
+
+        for (key, before) in self.sorted_concepts() {
+            let Some(after) = other.concepts.get(key) else {
+                continue;
+            };
+            let change = ConceptDiff {
+                concept: other.display_name(key).to_string(),
+                definitions_added: only_new(&after.definitions, &before.definitions),
+                definitions_removed: only_new(&before.definitions, &after.definitions),
+                examples_added: sorted_examples(&after.examples, &before.examples),
+                examples_removed: sorted_examples(&before.examples, &after.examples),
+                related_added: only_in(&after.related_concepts, &before.related_concepts, other),
+                related_removed: only_in(&before.related_concepts, &after.related_concepts, self),
+            };
+            if !change.is_empty() {
+                diff.changed.push(change);
+            }
+        }
+
+        diff
+    }
+
+    // Concepts whose name, definition or any example contains the term,
+    // ignoring case, sorted by name.
+    pub fn search(&self, term: &str) -> Vec<(&String, &Concept)> {
+        let term = term.to_lowercase();
+        let matches = |text: &str| text.to_lowercase().contains(&term);
+
+        let mut found: Vec<(&String, &Concept)> = self
+            .concepts
+            .iter()
+            .filter(|(key, details)| {
+                matches(self.display_name(key))
+                    || details.definitions.iter().any(|definition| matches(definition))
+                    || details.examples.iter().any(|example| matches(example))
+            })
+            .collect();
+        found.sort_by_key(|(key, _)| self.display_name(key).to_lowercase());
+        found
+    }
+
+    // Concepts ordered by degree: their own related concepts plus references
+    // from other concepts. Ties are broken by name.
+    pub fn rank_by_connectivity(&self) -> Vec<(String, usize)> {
+        let mut degree: HashMap<&str, usize> = HashMap::new();
+        for (concept, details) in &self.concepts {
+            *degree.entry(concept).or_default() += details.related_concepts.len();
+            for rc in details.related_concepts.keys() {
+                if rc != concept && self.concepts.contains_key(rc) {
+                    *degree.entry(rc).or_default() += 1;
+                }
+            }
+        }
+
+        let mut ranked: Vec<(String, usize)> = degree
+            .into_iter()
+            .map(|(key, count)| (self.display_name(key).to_string(), count))
+            .collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        ranked
+    }
+
+    // Cycles found by a depth-first walk over related_concepts: each back edge
+    // yields the path from its target around to its source. Not every
+    // elementary cycle is listed, but every cyclic region shows up at least once.
+    pub fn find_cycles(&self) -> Vec<Vec<String>> {
+        let mut keys: Vec<&String> = self.concepts.keys().collect();
+        keys.sort();
+
+        let mut visited: HashSet<&str> = HashSet::new();
+        let mut cycles = Vec::new();
+        for key in keys {
+            if !visited.contains(key.as_str()) {
+                let mut path = Vec::new();
+                self.walk_for_cycles(key, &mut path, &mut visited, &mut cycles);
+            }
+        }
+        cycles
+    }
+
+    fn walk_for_cycles<'a>(
+        &'a self,
+        concept: &'a str,
+        path: &mut Vec<&'a str>,
+        visited: &mut HashSet<&'a str>,
+        cycles: &mut Vec<Vec<String>>,
+    ) {
+        visited.insert(concept);
+        path.push(concept);
+
+        if let Some(details) = self.concepts.get(concept) {
+            let mut related: Vec<&String> = details.related_concepts.keys().collect();
+            related.sort();
+
+            for next in related {
+                if let Some(start) = path.iter().position(|c| *c == next.as_str()) {
+                    cycles.push(path[start..].iter().map(|c| c.to_string()).collect());
+                } else if !visited.contains(next.as_str()) {
+                    self.walk_for_cycles(next, path, visited, cycles);
+                }
+            }
+        }
+
+        path.pop();
+    }
+
+    // Notes the prompt behind a concept when sources are being tracked
+    pub fn add_source(&mut self, concept: &str, prompt: &str) {
+        if !self.options.track_sources {
+            return;
+        }
+        let key = self.concept_key(concept);
+        if let Some(entry) = self.concepts.get_mut(&key) {
+            entry.source_prompts.insert(prompt.to_string());
+        }
+    }
+
+    // `source` is the exploration prompt whose answer the insights came from
+    pub fn add_insights(&mut self, insights: Vec<StructuredInsight>, source: &str) {
+        for insight in insights {
+            // A filtered topic is left out as well, so it never becomes a
+            // concept that later rounds would expand
+            let topic = insight.topic.as_ref().filter(|topic| self.options.allows(topic));
+
+            if let Some(concept) = &insight.concept {
+                if !self.options.allows(concept) {
+                    debug!("Skipping filtered concept '{}'", concept);
+                    continue;
+                }
+                self.add_concept(concept.clone());
+                self.add_source(concept, source);
+
+                if let Some(topic) = topic {
+                    self.add_related_concept(concept, topic.clone(), insight.relation.as_deref());
+                }
+
+                if let Some(def) = &insight.definition {
+                    self.add_definition(concept.clone(), def.clone(), insight.confidence);
+                }
+
+                if let Some(ex) = &insight.example {
+                    self.add_example(concept, ex.clone(), insight.confidence);
+                }
+            } else {
+                if insight.definition.is_some() || insight.example.is_some() {
+                    warn!("Dropping insight with no concept: {:?}", insight);
+                }
+                if let Some(topic) = topic {
+                    // No concept to hang the topic on
+                    self.add_concept("General".to_string());
+                    self.add_related_concept("General", topic.clone(), None);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune_drops_only_concepts_without_content() {
+        let mut knowledge = Knowledge::default();
+        knowledge.add_concept("Empty".to_string());
+        knowledge.add_definition("Defined".to_string(), "Has a definition.".to_string(), None);
+        knowledge.add_example("Illustrated", "Has an example.".to_string(), None);
+        knowledge.add_related_concept("Linked", "Defined".to_string(), None);
+
+        assert_eq!(knowledge.prune(), 1);
+
+        let mut left: Vec<&String> = knowledge.concepts.keys().collect();
+        left.sort();
+        assert_eq!(left, ["Defined", "Illustrated", "Linked"]);
+    }
+
+    fn concept(definitions: &[&str], examples: &[&str], related: &[(&str, &str)]) -> Concept {
+        Concept {
+            definitions: definitions.iter().map(|d| d.to_string()).collect(),
+            examples: examples.iter().map(|e| e.to_string()).collect(),
+            related_concepts: related
+                .iter()
+                .map(|(target, relation)| (target.to_string(), relation.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    // Spellings a graph built without case folding ends up with
+    fn messy_graph() -> Knowledge {
+        let mut knowledge = Knowledge::default();
+        let concepts = [
+            ("Gravity", concept(&["A force."], &["Apple"], &[("mass energy", "depends-on")])),
+            (
+                " GRAVITY ",
+                concept(&["Attraction between masses."], &["Moon"], &[("gravity", "related")]),
+            ),
+            ("gravity", concept(&[], &[], &[("Mass  Energy", "related")])),
+            ("Mass  Energy", concept(&[], &[], &[])),
+            ("mass energy", concept(&["E = mc²"], &[], &[])),
+        ];
+        for (key, details) in concepts {
+            knowledge.concepts.insert(key.to_string(), details);
+        }
+        knowledge.expanded.insert("gravity".to_string());
+        knowledge
+    }
+
+    #[test]
+    fn normalize_folds_names_differing_by_case_or_spacing() {
+        let mut knowledge = messy_graph();
+
+        assert_eq!(knowledge.normalize(), 3);
+
+        let mut keys: Vec<&String> = knowledge.concepts.keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["GRAVITY", "Mass Energy"]);
+
+        let gravity = &knowledge.concepts["GRAVITY"];
+        let mut definitions = gravity.definitions.clone();
+        definitions.sort();
+        assert_eq!(definitions, ["A force.", "Attraction between masses."]);
+        assert_eq!(gravity.examples, HashSet::from(["Apple".to_string(), "Moon".to_string()]));
+        // The specific type survives and the edge to a former variant of itself is gone
+        assert_eq!(
+            gravity.related_concepts,
+            HashMap::from([("Mass Energy".to_string(), "depends-on".to_string())])
+        );
+        assert_eq!(knowledge.concepts["Mass Energy"].definitions, ["E = mc²"]);
+        assert_eq!(knowledge.expanded, HashSet::from(["GRAVITY".to_string()]));
+    }
+
+    #[test]
+    fn normalize_keeps_the_longer_definition_with_single_definition() {
+        let mut knowledge = messy_graph();
+        knowledge.options.single_definition = true;
+
+        knowledge.normalize();
+
+        assert_eq!(knowledge.concepts["GRAVITY"].definitions, ["Attraction between masses."]);
+    }
+
+    #[test]
+    fn normalize_keeps_a_display_name_under_the_insensitive_policy() {
+        let mut knowledge = messy_graph();
+        knowledge.options.case_policy = CasePolicy::Insensitive;
+
+        knowledge.normalize();
+
+        let mut keys: Vec<&String> = knowledge.concepts.keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["gravity", "mass energy"]);
+        assert_eq!(knowledge.display_name("gravity"), "GRAVITY");
+        assert_eq!(knowledge.display_name("mass energy"), "Mass Energy");
+    }
+
+    #[test]
+    fn a_relation_to_another_spelling_of_itself_is_dropped() {
+        let mut knowledge = Knowledge::default();
+        knowledge.options.case_policy = CasePolicy::Insensitive;
+        knowledge.options.bidirectional = true;
+        knowledge.add_concept("Gravity".to_string());
+
+        knowledge.add_related_concept("Gravity", " gravity ".to_string(), None);
+
+        assert!(knowledge.concepts["gravity"].related_concepts.is_empty());
+        assert_eq!(knowledge.concepts.len(), 1);
+    }
+}
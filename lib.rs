@@ -0,0 +1,12 @@
+pub mod client;
+pub mod config;
+pub mod error;
+pub mod explore;
+pub mod knowledge;
+pub mod output;
+
+pub use client::{HttpModel, ModelClient, Prompt, build_client};
+pub use config::Config;
+pub use error::{FetchError, HttpError};
+pub use explore::{StopReason, build_documentation};
+pub use knowledge::{Concept, Knowledge};
@@ -0,0 +1,591 @@
+use clap::ValueEnum;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use tracing::{info, warn};
+
+use crate::error::FetchError;
+use crate::knowledge::{Concept, Knowledge, RELATED};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Plain text dump written to documentation.txt
+    Text,
+    /// GraphViz graph written to documentation.dot
+    Dot,
+    /// Full knowledge graph written to documentation.json
+    Json,
+    /// Markdown with a table of contents written to documentation.md
+    Markdown,
+    /// One row per concept written to documentation.csv
+    Csv,
+    /// Full knowledge graph written to documentation.yaml
+    Yaml,
+    /// Gephi graph with definitions as node attributes, written to documentation.gexf
+    Gexf,
+    /// Standalone page with a collapsible section per concept, written to documentation.html
+    Html,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Text => "txt",
+            OutputFormat::Dot => "dot",
+            OutputFormat::Json => "json",
+            OutputFormat::Markdown => "md",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Yaml => "yaml",
+            OutputFormat::Gexf => "gexf",
+            OutputFormat::Html => "html",
+        }
+    }
+}
+
+// Makes sure the directory a writer is about to create a file in exists
+pub fn create_parent_dir(path: &Path) -> Result<(), FetchError> {
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+
+// With `append`, an existing file is kept and this run is added after a
+// separator line
+pub fn write_documentation_to_file(
+    knowledge: &Knowledge,
+    path: &Path,
+    append: bool,
+) -> Result<(), FetchError> {
+    let mut file = if append {
+        std::fs::OpenOptions::new().create(true).append(true).open(path)?
+    } else {
+        std::fs::File::create(path)?
+    };
+    if append && file.metadata()?.len() > 0 {
+        writeln!(file, "{}", "=".repeat(40))?;
+        writeln!(file)?;
+    }
+
+    for (concept, details) in knowledge.sorted_concepts() {
+        write_concept_text(&mut file, knowledge, concept, details)?;
+    }
+
+    Ok(())
+}
+
+fn write_concept_text(
+    out: &mut impl Write,
+    knowledge: &Knowledge,
+    concept: &str,
+    details: &Concept,
+) -> io::Result<()> {
+    writeln!(out, "Concept: {}", knowledge.display_name(concept))?;
+
+    for def in &details.definitions {
+        let score = details.definition_confidence.get(def).copied();
+        writeln!(out, "  Definition: {}{}", def, confidence_note(score))?;
+    }
+
+    if !details.examples.is_empty() {
+        writeln!(out, "  Examples:")?;
+        let mut examples: Vec<&String> = details.examples.iter().collect();
+        examples.sort();
+        for example in examples {
+            let score = details.example_confidence.get(example).copied();
+            writeln!(out, "    - {}{}", example, confidence_note(score))?;
+        }
+    }
+
+    if !details.related_concepts.is_empty() {
+        writeln!(out, "  Related Concepts:")?;
+        for (rc, relation) in knowledge.sorted_related(details) {
+            writeln!(out, "    - {}", relation_label(rc, relation))?;
+        }
+    }
+
+    writeln!(out)
+}
+
+// The text output, written a concept at a time while exploration runs
+// (--live-text) so the file can be followed with tail -f. Each concept is
+// written once, when its caller says it is settled; anything added to it
+// afterwards only reaches the final output. Shared by every clone of the
+// config, and the file is created on the first write.
+#[derive(Debug)]
+pub struct LiveText {
+    path: PathBuf,
+    state: Mutex<LiveState>,
+}
+
+#[derive(Debug, Default)]
+struct LiveState {
+    file: Option<File>,
+    written: HashSet<String>,
+}
+
+impl LiveText {
+    pub fn new(path: PathBuf) -> Self {
+        LiveText {
+            path,
+            state: Mutex::new(LiveState::default()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Appends the settled concepts not written yet and returns how many
+    pub fn write_settled(
+        &self,
+        knowledge: &Knowledge,
+        settled: impl Fn(&str) -> bool,
+    ) -> Result<usize, FetchError> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut text = Vec::new();
+        let mut added = Vec::new();
+        for (key, details) in knowledge.sorted_concepts() {
+            if settled(key) && !state.written.contains(key) {
+                write_concept_text(&mut text, knowledge, key, details)?;
+                added.push(key.clone());
+            }
+        }
+        if added.is_empty() {
+            return Ok(0);
+        }
+
+        if state.file.is_none() {
+            create_parent_dir(&self.path)?;
+            state.file = Some(File::create(&self.path)?);
+        }
+        if let Some(file) = state.file.as_mut() {
+            file.write_all(&text)?;
+            file.flush()?;
+        }
+        state.written.extend(added.iter().cloned());
+        Ok(added.len())
+    }
+}
+
+// A related concept's name, with its relation type unless that is generic
+pub fn relation_label(name: &str, relation: &str) -> String {
+    if relation == RELATED {
+        name.to_string()
+    } else {
+        format!("{} ({})", name, relation)
+    }
+}
+
+fn confidence_note(score: Option<f64>) -> String {
+    score.map_or_else(String::new, |s| format!(" (confidence {:.2})", s))
+}
+
+pub fn write_documentation_to_dot(knowledge: &Knowledge, path: &Path) -> Result<(), FetchError> {
+    let mut file = std::fs::File::create(path)?;
+
+    writeln!(file, "digraph knowledge {{")?;
+
+    for (concept, details) in knowledge.sorted_concepts() {
+        let name = escape_dot(knowledge.display_name(concept));
+        writeln!(file, "  \"{}\";", name)?;
+
+        for (rc, relation) in knowledge.sorted_related(details) {
+            writeln!(
+                file,
+                "  \"{}\" -> \"{}\" [label=\"{}\"];",
+                name,
+                escape_dot(rc),
+                escape_dot(relation)
+            )?;
+        }
+    }
+
+    writeln!(file, "}}")?;
+
+    Ok(())
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\r', "")
+        .replace('\n', "\\n")
+}
+
+pub fn write_documentation_to_gexf(knowledge: &Knowledge, path: &Path) -> Result<(), FetchError> {
+    let mut file = std::fs::File::create(path)?;
+
+    // Ids follow the sorted concept order, then related names that have no
+    // entry of their own, so the same graph always gets the same ids
+    let concepts = knowledge.sorted_concepts();
+    let mut dangling: Vec<&String> = concepts
+        .iter()
+        .flat_map(|(_, details)| details.related_concepts.keys())
+        .filter(|rc| !knowledge.concepts.contains_key(*rc))
+        .collect();
+    dangling.sort();
+    dangling.dedup();
+
+    let mut ids: HashMap<&str, usize> = HashMap::new();
+    for key in concepts.iter().map(|(key, _)| *key).chain(dangling.iter().copied()) {
+        let next = ids.len();
+        ids.entry(key.as_str()).or_insert(next);
+    }
+
+    writeln!(file, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(file, "<gexf xmlns=\"http://gexf.net/1.3\" version=\"1.3\">")?;
+    writeln!(file, "  <graph defaultedgetype=\"directed\">")?;
+    writeln!(file, "    <attributes class=\"node\">")?;
+    writeln!(file, "      <attribute id=\"0\" title=\"definition\" type=\"string\"/>")?;
+    writeln!(file, "    </attributes>")?;
+
+    writeln!(file, "    <nodes>")?;
+    for (key, details) in &concepts {
+        let id = ids[key.as_str()];
+        let label = escape_xml(knowledge.display_name(key));
+        if details.has_definition() {
+            writeln!(file, "      <node id=\"{}\" label=\"{}\">", id, label)?;
+            writeln!(file, "        <attvalues>")?;
+            writeln!(
+                file,
+                "          <attvalue for=\"0\" value=\"{}\"/>",
+                escape_xml(&details.definitions.join("\n"))
+            )?;
+            writeln!(file, "        </attvalues>")?;
+            writeln!(file, "      </node>")?;
+        } else {
+            writeln!(file, "      <node id=\"{}\" label=\"{}\"/>", id, label)?;
+        }
+    }
+    for key in &dangling {
+        let label = escape_xml(knowledge.display_name(key));
+        writeln!(file, "      <node id=\"{}\" label=\"{}\"/>", ids[key.as_str()], label)?;
+    }
+    writeln!(file, "    </nodes>")?;
+
+    writeln!(file, "    <edges>")?;
+    let mut edge_id = 0;
+    for (key, details) in &concepts {
+        let mut targets: Vec<(&String, &String)> = details.related_concepts.iter().collect();
+        targets.sort();
+        for (target, relation) in targets {
+            writeln!(
+                file,
+                "      <edge id=\"{}\" source=\"{}\" target=\"{}\" label=\"{}\"/>",
+                edge_id,
+                ids[key.as_str()],
+                ids[target.as_str()],
+                escape_xml(relation)
+            )?;
+            edge_id += 1;
+        }
+    }
+    writeln!(file, "    </edges>")?;
+
+    writeln!(file, "  </graph>")?;
+    writeln!(file, "</gexf>")?;
+
+    Ok(())
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\r', "")
+        .replace('\n', "&#10;")
+}
+
+pub fn write_documentation_to_json(knowledge: &Knowledge, path: &Path) -> Result<(), FetchError> {
+    let file = std::fs::File::create(path)?;
+    serde_json::to_writer_pretty(file, knowledge)?;
+    Ok(())
+}
+
+pub fn write_documentation_to_yaml(knowledge: &Knowledge, path: &Path) -> Result<(), FetchError> {
+    let file = std::fs::File::create(path)?;
+    serde_yaml::to_writer(file, knowledge)?;
+    Ok(())
+}
+
+pub fn load_knowledge(path: &Path) -> Knowledge {
+    match read_knowledge(path) {
+        Ok(knowledge) => {
+            info!("Resumed {} concepts from {}", knowledge.concepts.len(), path.display());
+            knowledge
+        }
+        Err(e) => {
+            warn!(
+                "Failed to load knowledge from {}: {}\nStarting with an empty graph.",
+                path.display(),
+                e
+            );
+            Knowledge::default()
+        }
+    }
+}
+
+// Writes to a sibling temp file and renames it over the target, so an
+// interrupted write never leaves a truncated checkpoint behind.
+pub fn write_json_atomically(knowledge: &Knowledge, path: &Path) -> Result<(), FetchError> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let mut file = std::fs::File::create(&tmp_path)?;
+    serde_json::to_writer_pretty(&mut file, knowledge)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+pub fn read_aliases(path: &Path) -> Result<HashMap<String, String>, FetchError> {
+    let json = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+// Reads a graph saved by the JSON or YAML writer, picked by file extension
+pub fn read_knowledge(path: &Path) -> Result<Knowledge, FetchError> {
+    let text = std::fs::read_to_string(path)?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml" | "yml") => Ok(serde_yaml::from_str(&text)?),
+        _ => Ok(serde_json::from_str(&text)?),
+    }
+}
+
+pub fn write_documentation_to_markdown(
+    knowledge: &Knowledge,
+    path: &Path,
+) -> Result<(), FetchError> {
+    let mut file = std::fs::File::create(path)?;
+
+    let concepts: Vec<(&str, &String, &Concept)> = knowledge
+        .sorted_concepts()
+        .into_iter()
+        .map(|(key, details)| (knowledge.display_name(key), key, details))
+        .collect();
+
+    let anchors = unique_anchors(&concepts);
+
+    writeln!(file, "# Documentation")?;
+    writeln!(file)?;
+    writeln!(file, "## Table of Contents")?;
+    writeln!(file)?;
+    for (name, key, _) in &concepts {
+        writeln!(file, "- [{}](#{})", name, anchors[key])?;
+    }
+
+    for (name, _, details) in &concepts {
+        writeln!(file)?;
+        writeln!(file, "## {}", name)?;
+
+        for def in &details.definitions {
+            writeln!(file)?;
+            writeln!(file, "{}", def)?;
+        }
+
+        if !details.examples.is_empty() {
+            let mut examples: Vec<&String> = details.examples.iter().collect();
+            examples.sort();
+            writeln!(file)?;
+            writeln!(file, "**Examples**")?;
+            writeln!(file)?;
+            for example in examples {
+                writeln!(file, "- {}", example)?;
+            }
+        }
+
+        if !details.related_concepts.is_empty() {
+            let mut related: Vec<(&String, &String)> = details.related_concepts.iter().collect();
+            related.sort_by_key(|(rc, _)| knowledge.display_name(rc).to_lowercase());
+            writeln!(file)?;
+            writeln!(file, "**Related Concepts**")?;
+            writeln!(file)?;
+            for (rc, relation) in related {
+                let name = knowledge.display_name(rc);
+                let note = if relation == RELATED {
+                    String::new()
+                } else {
+                    format!(" ({})", relation)
+                };
+                match anchors.get(rc) {
+                    Some(anchor) => writeln!(file, "- [{}](#{}){}", name, anchor, note)?,
+                    None => writeln!(file, "- {}{}", name, note)?,
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// GitHub suffixes repeated heading slugs with -1, -2, ...
+fn unique_anchors<'a>(concepts: &[(&str, &'a String, &Concept)]) -> HashMap<&'a String, String> {
+    let mut anchors: HashMap<&String, String> = HashMap::new();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for (name, key, _) in concepts {
+        let slug = markdown_anchor(name);
+        let count = seen.entry(slug.clone()).or_insert(0);
+        let anchor = if *count == 0 { slug } else { format!("{}-{}", slug, count) };
+        *count += 1;
+        anchors.insert(key, anchor);
+    }
+    anchors
+}
+
+// Heading anchor as generated by GitHub-flavoured Markdown renderers.
+pub fn markdown_anchor(heading: &str) -> String {
+    heading
+        .trim()
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+const HTML_STYLE: &str = "body { font-family: sans-serif; max-width: 50em; margin: 2em auto; }
+details { border: 1px solid #ccc; border-radius: 4px; margin: 0.5em 0; padding: 0.5em 1em; }
+summary { cursor: pointer; font-weight: bold; }
+p { white-space: pre-wrap; }
+.relation { color: #666; }";
+
+// Links point at closed sections, so the target is opened on arrival
+const HTML_SCRIPT: &str = "function openTarget() {
+  var target = document.getElementById(decodeURIComponent(location.hash.slice(1)));
+  if (target && target.tagName === 'DETAILS') target.open = true;
+}
+window.addEventListener('hashchange', openTarget);
+openTarget();";
+
+// A single file with inline CSS and no external assets, so it can be
+// mailed or opened from disk as is
+pub fn write_documentation_to_html(knowledge: &Knowledge, path: &Path) -> Result<(), FetchError> {
+    let mut file = std::fs::File::create(path)?;
+
+    let concepts: Vec<(&str, &String, &Concept)> = knowledge
+        .sorted_concepts()
+        .into_iter()
+        .map(|(key, details)| (knowledge.display_name(key), key, details))
+        .collect();
+    // Prefixed so an id is never empty and never clashes with the page's own
+    let ids: HashMap<&String, String> = unique_anchors(&concepts)
+        .into_iter()
+        .map(|(key, anchor)| (key, escape_html(&format!("concept-{}", anchor))))
+        .collect();
+
+    writeln!(file, "<!DOCTYPE html>")?;
+    writeln!(file, "<html>")?;
+    writeln!(file, "<head>")?;
+    writeln!(file, "<meta charset=\"utf-8\">")?;
+    writeln!(file, "<title>Documentation</title>")?;
+    writeln!(file, "<style>\n{}\n</style>", HTML_STYLE)?;
+    writeln!(file, "</head>")?;
+    writeln!(file, "<body>")?;
+    writeln!(file, "<h1>Documentation</h1>")?;
+
+    for (name, key, details) in &concepts {
+        writeln!(file, "<details id=\"{}\">", ids[key])?;
+        writeln!(file, "<summary>{}</summary>", escape_html(name))?;
+
+        for def in &details.definitions {
+            writeln!(file, "<p>{}</p>", escape_html(def))?;
+        }
+
+        if !details.examples.is_empty() {
+            let mut examples: Vec<&String> = details.examples.iter().collect();
+            examples.sort();
+            writeln!(file, "<h3>Examples</h3>")?;
+            writeln!(file, "<ul>")?;
+            for example in examples {
+                writeln!(file, "<li>{}</li>", escape_html(example))?;
+            }
+            writeln!(file, "</ul>")?;
+        }
+
+        if !details.related_concepts.is_empty() {
+            let mut related: Vec<(&String, &String)> = details.related_concepts.iter().collect();
+            related.sort_by_key(|(rc, _)| knowledge.display_name(rc).to_lowercase());
+            writeln!(file, "<h3>Related Concepts</h3>")?;
+            writeln!(file, "<ul>")?;
+            for (rc, relation) in related {
+                let name = escape_html(knowledge.display_name(rc));
+                let note = if relation == RELATED {
+                    String::new()
+                } else {
+                    format!(" <span class=\"relation\">({})</span>", escape_html(relation))
+                };
+                match ids.get(rc) {
+                    Some(id) => {
+                        writeln!(file, "<li><a href=\"#{}\">{}</a>{}</li>", id, name, note)?
+                    }
+                    None => writeln!(file, "<li>{}{}</li>", name, note)?,
+                }
+            }
+            writeln!(file, "</ul>")?;
+        }
+
+        writeln!(file, "</details>")?;
+    }
+
+    writeln!(file, "<script>\n{}\n</script>", HTML_SCRIPT)?;
+    writeln!(file, "</body>")?;
+    writeln!(file, "</html>")?;
+    Ok(())
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+pub fn write_documentation_to_csv(knowledge: &Knowledge, path: &Path) -> Result<(), FetchError> {
+    let mut file = std::fs::File::create(path)?;
+
+    // RFC 4180 records end in CRLF
+    write!(file, "name,definitions,examples,related_concepts\r\n")?;
+
+    for (concept, details) in knowledge.sorted_concepts() {
+        let mut examples: Vec<&str> = details.examples.iter().map(String::as_str).collect();
+        examples.sort();
+        let related: Vec<String> = knowledge
+            .sorted_related(details)
+            .into_iter()
+            .map(|(rc, relation)| relation_label(rc, relation))
+            .collect();
+
+        write!(
+            file,
+            "{},{},{},{}\r\n",
+            csv_field(knowledge.display_name(concept)),
+            csv_field(&details.definitions.join("; ")),
+            csv_field(&examples.join("; ")),
+            csv_field(&related.join("; "))
+        )?;
+    }
+
+    Ok(())
+}
+
+// Quotes a field when it contains a delimiter, quote or line break, doubling
+// any embedded quotes.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}